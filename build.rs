@@ -1,11 +1,18 @@
 use flatc_rust::run;
 
 use mktemp::Temp;
-use std::{fs, fs::File, io, io::Write, path::Path};
+use std::{fs, fs::File, io, io::Write, ops::Range, path::Path};
 
 fn generate_chess_flatbuff() -> Result<(), std::io::Error> {
+    let schema = fs::read_to_string("chess_flat_buffer/chess.fbs")?;
+    let extensions = fs::read_to_string("chess_extensions.fbs")?;
+
+    let merged_path = Path::new("target/flatbuffers/chess.fbs");
+    fs::create_dir_all("target/flatbuffers/")?;
+    fs::write(merged_path, merge_schema(&schema, &extensions))?;
+
     run(flatc_rust::Args {
-        inputs: &[Path::new("chess_flat_buffer/chess.fbs")],
+        inputs: &[merged_path],
         out_dir: Path::new("target/flatbuffers/"),
         ..Default::default()
     })
@@ -19,9 +26,74 @@ fn generate_chess_flatbuff() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// The name and the range of the body, between the braces, of each table in `schema`
+fn tables(schema: &str) -> Vec<(&str, Range<usize>)> {
+    let mut tables = vec![];
+    let mut pos = 0;
+
+    while let Some(found) = schema[pos..].find("table") {
+        let keyword = pos + found;
+        pos = keyword + "table".len();
+
+        // Only the keyword at the start of a line, not a comment or field
+        let line_start = schema[..keyword].rfind('\n').map_or(0, |i| i + 1);
+        if !schema[line_start..keyword].trim().is_empty() {
+            continue;
+        }
+
+        let rest = &schema[pos..];
+        if !rest.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let (open, close) = match (rest.find('{'), rest.find('}')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => continue,
+        };
+        let name = rest[..open].trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            continue;
+        }
+
+        tables.push((name, pos + open + 1..pos + close));
+        pos += close + 1;
+    }
+
+    tables
+}
+
+/// The submodule's schema with the fields of each table in `extensions`
+/// appended to the table of the same name, so their ids follow the ids of the
+/// fields already there, and the tables it does not have added to the end
+fn merge_schema(schema: &str, extensions: &str) -> String {
+    let existing = tables(schema);
+    let mut appended_fields = vec![];
+    let mut new_tables = String::new();
+
+    for (name, body) in tables(extensions) {
+        match existing.iter().find(|(existing, _)| *existing == name) {
+            Some((_, existing_body)) => {
+                appended_fields.push((existing_body.end, &extensions[body]))
+            }
+            None => new_tables.push_str(&format!("\ntable {} {{{}}}\n", name, &extensions[body])),
+        }
+    }
+
+    // Insert from the end so the positions of the earlier tables still hold
+    appended_fields.sort_by_key(|(end, _)| std::cmp::Reverse(*end));
+    let mut merged = schema.to_string();
+    for (end, fields) in appended_fields {
+        merged.insert_str(end, fields);
+    }
+    merged.push_str(&new_tables);
+
+    merged
+}
+
 fn main() -> io::Result<()> {
     println!("cargo:rerun-if-changed=./build.rs");
     println!("cargo:rerun-if-changed=./Cargo.lock");
+    println!("cargo:rerun-if-changed=./chess_flat_buffer/chess.fbs");
+    println!("cargo:rerun-if-changed=./chess_extensions.fbs");
 
     generate_chess_flatbuff()?;
 
@@ -35,12 +107,12 @@ fn prepend_file<P: AsRef<Path>>(data: &[u8], file_path: &P) -> io::Result<()> {
     // Open temp file for writing
     let mut tmp = File::create(&tmp_path)?;
     // Open source file for reading
-    let mut src = File::open(&file_path)?;
+    let mut src = File::open(file_path)?;
     // Write the data to prepend
     tmp.write_all(data)?;
     // Copy the rest of the source file
     io::copy(&mut src, &mut tmp)?;
-    fs::remove_file(&file_path)?;
-    fs::copy(&tmp_path, &file_path)?;
+    fs::remove_file(file_path)?;
+    fs::copy(&tmp_path, file_path)?;
     Ok(())
 }
@@ -0,0 +1,737 @@
+// A minimal mailbox board, used to replay games and resolve SAN moves to
+// from/to squares. Squares are numbered a1 = 0, b1 = 1, ..., h8 = 63.
+
+pub type Square = u8;
+
+pub fn square(file: u8, rank: u8) -> Square {
+    rank * 8 + file
+}
+
+pub fn file_of(square: Square) -> u8 {
+    square % 8
+}
+
+pub fn rank_of(square: Square) -> u8 {
+    square / 8
+}
+
+/// Parse a coordinate such as `e4`
+pub fn parse_square(coord: &str) -> Option<Square> {
+    match coord.as_bytes() {
+        [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Some(square(file - b'a', rank - b'1')),
+        _ => None,
+    }
+}
+
+pub fn square_name(square: Square) -> String {
+    format!(
+        "{}{}",
+        (b'a' + file_of(square)) as char,
+        (b'1' + rank_of(square)) as char
+    )
+}
+
+/// Parse a SAN disambiguation (`""`, `b`, `1` or `b1`) into the file and rank it constrains
+pub fn parse_disambiguation(disambiguation: &str) -> Option<(Option<u8>, Option<u8>)> {
+    match disambiguation.as_bytes() {
+        [] => Some((None, None)),
+        [file @ b'a'..=b'h'] => Some((Some(file - b'a'), None)),
+        [rank @ b'1'..=b'8'] => Some((None, Some(rank - b'1'))),
+        [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Some((Some(file - b'a'), Some(rank - b'1'))),
+        _ => None,
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Color {
+    White = 0,
+    Black = 1,
+}
+
+impl Color {
+    pub fn opponent(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+
+    fn back_rank(self) -> u8 {
+        match self {
+            Color::White => 0,
+            Color::Black => 7,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum PieceKind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+impl PieceKind {
+    /// The piece for a SAN piece letter, where the empty string is a pawn
+    pub fn from_san(letter: &str) -> Option<PieceKind> {
+        match letter {
+            "" | "P" => Some(PieceKind::Pawn),
+            "N" => Some(PieceKind::Knight),
+            "B" => Some(PieceKind::Bishop),
+            "R" => Some(PieceKind::Rook),
+            "Q" => Some(PieceKind::Queen),
+            "K" => Some(PieceKind::King),
+            _ => None,
+        }
+    }
+
+    /// The SAN piece letter, empty for a pawn
+    pub fn to_san(self) -> &'static str {
+        match self {
+            PieceKind::Pawn => "",
+            PieceKind::Knight => "N",
+            PieceKind::Bishop => "B",
+            PieceKind::Rook => "R",
+            PieceKind::Queen => "Q",
+            PieceKind::King => "K",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct Piece {
+    pub kind: PieceKind,
+    pub color: Color,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum CastlingSide {
+    King = 0,
+    Queen = 1,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum MoveKind {
+    Normal,
+    EnPassant,
+    /// The king moves from `from` to `to` and the rook from `rook` to its castled square
+    Castle {
+        side: CastlingSide,
+        rook: Square,
+    },
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct Move {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<PieceKind>,
+    pub kind: MoveKind,
+}
+
+/// A move as UCI writes it: from-square, to-square and promotion piece
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct UciMove {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<PieceKind>,
+}
+
+impl Move {
+    /// Castling is written as the king's move, e.g. e1g1, or with `chess960`
+    /// as the king moving to its rook's square, e.g. e1h1.
+    pub fn uci(&self, chess960: bool) -> UciMove {
+        let to = match self.kind {
+            MoveKind::Castle { rook, .. } if chess960 => rook,
+            _ => self.to,
+        };
+
+        UciMove {
+            from: self.from,
+            to,
+            promotion: self.promotion,
+        }
+    }
+}
+
+const KNIGHT_STEPS: [(i8, i8); 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+const KING_STEPS: [(i8, i8); 8] = [
+    (0, 1),
+    (1, 1),
+    (1, 0),
+    (1, -1),
+    (0, -1),
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+];
+const ROOK_DIRECTIONS: [(i8, i8); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+const BISHOP_DIRECTIONS: [(i8, i8); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+const PROMOTIONS: [PieceKind; 4] = [
+    PieceKind::Knight,
+    PieceKind::Bishop,
+    PieceKind::Rook,
+    PieceKind::Queen,
+];
+
+fn offset(from: Square, (file_step, rank_step): (i8, i8)) -> Option<Square> {
+    let file = file_of(from) as i8 + file_step;
+    let rank = rank_of(from) as i8 + rank_step;
+
+    if (0..8).contains(&file) && (0..8).contains(&rank) {
+        Some(square(file as u8, rank as u8))
+    } else {
+        None
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Position {
+    squares: [Option<Piece>; 64],
+    side_to_move: Color,
+    /// The rook that can still castle, indexed by color then `CastlingSide`
+    castling_rooks: [[Option<Square>; 2]; 2],
+    en_passant: Option<Square>,
+}
+
+impl Position {
+    pub fn starting() -> Self {
+        let back_rank = [
+            PieceKind::Rook,
+            PieceKind::Knight,
+            PieceKind::Bishop,
+            PieceKind::Queen,
+            PieceKind::King,
+            PieceKind::Bishop,
+            PieceKind::Knight,
+            PieceKind::Rook,
+        ];
+
+        let mut squares = [None; 64];
+        for (file, kind) in back_rank.iter().enumerate() {
+            let file = file as u8;
+            squares[square(file, 0) as usize] = Some(Piece {
+                kind: *kind,
+                color: Color::White,
+            });
+            squares[square(file, 1) as usize] = Some(Piece {
+                kind: PieceKind::Pawn,
+                color: Color::White,
+            });
+            squares[square(file, 6) as usize] = Some(Piece {
+                kind: PieceKind::Pawn,
+                color: Color::Black,
+            });
+            squares[square(file, 7) as usize] = Some(Piece {
+                kind: *kind,
+                color: Color::Black,
+            });
+        }
+
+        Position {
+            squares,
+            side_to_move: Color::White,
+            castling_rooks: [
+                [Some(square(7, 0)), Some(square(0, 0))],
+                [Some(square(7, 7)), Some(square(0, 7))],
+            ],
+            en_passant: None,
+        }
+    }
+
+    /// Parse a position from FEN. The castling field may use `KQkq`, which
+    /// refers to the outermost rook on each side of the king, or the files of
+    /// the castling rooks (`HAha`) as in Shredder-FEN. The checks remaining in
+    /// a three-check FEN, e.g. `3+3` after the en passant square or `+0+0` at
+    /// the end, are skipped.
+    pub fn from_fen(fen: &str) -> Option<Self> {
+        let mut fields = fen.split_whitespace().filter(|field| {
+            !(field.contains('+') && field.chars().all(|c| c == '+' || c.is_ascii_digit()))
+        });
+        // Crazyhouse FENs may append the pieces in hand, e.g. `.../RNBQKBNR[Pp]`
+        let placement = fields.next()?.split('[').next()?;
+        let side_to_move = fields.next()?;
+        let castling = fields.next().unwrap_or("-");
+        let en_passant = fields.next().unwrap_or("-");
+
+        // The halfmove clock and fullmove number aren't needed to replay a game
+        for counter in fields.by_ref().take(2) {
+            counter.parse::<u32>().ok()?;
+        }
+        if fields.next().is_some() {
+            return None;
+        }
+
+        let mut squares = [None; 64];
+        let ranks: Vec<&str> = placement.split('/').collect();
+        if ranks.len() != 8 {
+            return None;
+        }
+
+        for (i, rank_str) in ranks.iter().enumerate() {
+            let rank = 7 - i as u8;
+            let mut file = 0;
+
+            for c in rank_str.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    file += empty as u8;
+                } else {
+                    let kind = match c.to_ascii_lowercase() {
+                        'p' => PieceKind::Pawn,
+                        'n' => PieceKind::Knight,
+                        'b' => PieceKind::Bishop,
+                        'r' => PieceKind::Rook,
+                        'q' => PieceKind::Queen,
+                        'k' => PieceKind::King,
+                        _ => return None,
+                    };
+                    let color = if c.is_ascii_uppercase() {
+                        Color::White
+                    } else {
+                        Color::Black
+                    };
+
+                    if file >= 8 {
+                        return None;
+                    }
+                    squares[square(file, rank) as usize] = Some(Piece { kind, color });
+                    file += 1;
+                }
+            }
+
+            if file != 8 {
+                return None;
+            }
+        }
+
+        let side_to_move = match side_to_move {
+            "w" => Color::White,
+            "b" => Color::Black,
+            _ => return None,
+        };
+
+        let mut position = Position {
+            squares,
+            side_to_move,
+            castling_rooks: [[None, None], [None, None]],
+            en_passant: match en_passant {
+                "-" => None,
+                coord => Some(parse_square(coord)?),
+            },
+        };
+
+        if castling != "-" {
+            for c in castling.chars() {
+                let color = if c.is_ascii_uppercase() {
+                    Color::White
+                } else {
+                    Color::Black
+                };
+                let back_rank = color.back_rank();
+                let king_file = file_of(position.king_square(color)?);
+                let rook = Piece {
+                    kind: PieceKind::Rook,
+                    color,
+                };
+                let is_rook =
+                    |file: &u8| position.squares[square(*file, back_rank) as usize] == Some(rook);
+
+                let rook_file = match c.to_ascii_lowercase() {
+                    'k' => (king_file + 1..8).rev().find(is_rook)?,
+                    'q' => (0..king_file).find(is_rook)?,
+                    file @ 'a'..='h' => file as u8 - b'a',
+                    _ => return None,
+                };
+
+                let side = if rook_file > king_file {
+                    CastlingSide::King
+                } else {
+                    CastlingSide::Queen
+                };
+                position.castling_rooks[color as usize][side as usize] =
+                    Some(square(rook_file, back_rank));
+            }
+        }
+
+        Some(position)
+    }
+
+    pub fn side_to_move(&self) -> Color {
+        self.side_to_move
+    }
+
+    pub fn piece_at(&self, square: Square) -> Option<Piece> {
+        self.squares[square as usize]
+    }
+
+    fn king_square(&self, color: Color) -> Option<Square> {
+        (0..64).find(|&sq| {
+            self.squares[sq as usize]
+                == Some(Piece {
+                    kind: PieceKind::King,
+                    color,
+                })
+        })
+    }
+
+    /// Whether any piece of color `by` attacks `target`
+    pub fn is_attacked(&self, target: Square, by: Color) -> bool {
+        let is = |square: Option<Square>, kinds: &[PieceKind]| match square
+            .and_then(|sq| self.squares[sq as usize])
+        {
+            Some(piece) => piece.color == by && kinds.contains(&piece.kind),
+            None => false,
+        };
+
+        if KNIGHT_STEPS
+            .iter()
+            .any(|&step| is(offset(target, step), &[PieceKind::Knight]))
+        {
+            return true;
+        }
+
+        if KING_STEPS
+            .iter()
+            .any(|&step| is(offset(target, step), &[PieceKind::King]))
+        {
+            return true;
+        }
+
+        // A pawn of color `by` attacks diagonally forwards, so look backwards from the target
+        let pawn_rank_step = match by {
+            Color::White => -1,
+            Color::Black => 1,
+        };
+        if [(-1, pawn_rank_step), (1, pawn_rank_step)]
+            .iter()
+            .any(|&step| is(offset(target, step), &[PieceKind::Pawn]))
+        {
+            return true;
+        }
+
+        let sliders = [
+            (ROOK_DIRECTIONS, [PieceKind::Rook, PieceKind::Queen]),
+            (BISHOP_DIRECTIONS, [PieceKind::Bishop, PieceKind::Queen]),
+        ];
+        for (directions, kinds) in sliders.iter() {
+            for &direction in directions.iter() {
+                let mut current = offset(target, direction);
+                while let Some(sq) = current {
+                    if self.squares[sq as usize].is_some() {
+                        if is(Some(sq), kinds) {
+                            return true;
+                        }
+                        break;
+                    }
+                    current = offset(sq, direction);
+                }
+            }
+        }
+
+        false
+    }
+
+    pub fn in_check(&self, color: Color) -> bool {
+        match self.king_square(color) {
+            Some(king) => self.is_attacked(king, color.opponent()),
+            None => false,
+        }
+    }
+
+    fn push_pawn_move(&self, from: Square, to: Square, kind: MoveKind, moves: &mut Vec<Move>) {
+        let last_rank = self.side_to_move.opponent().back_rank();
+
+        if rank_of(to) == last_rank {
+            for promotion in PROMOTIONS.iter() {
+                moves.push(Move {
+                    from,
+                    to,
+                    promotion: Some(*promotion),
+                    kind,
+                });
+            }
+        } else {
+            moves.push(Move {
+                from,
+                to,
+                promotion: None,
+                kind,
+            });
+        }
+    }
+
+    /// Moves of the piece on `from` that ignore whether the mover's king is left in check
+    fn pseudo_legal_moves_from(&self, from: Square, moves: &mut Vec<Move>) {
+        let piece = match self.squares[from as usize] {
+            Some(piece) if piece.color == self.side_to_move => piece,
+            _ => return,
+        };
+
+        let normal = |to: Square| Move {
+            from,
+            to,
+            promotion: None,
+            kind: MoveKind::Normal,
+        };
+        let is_enemy =
+            |sq: Square| self.squares[sq as usize].is_some_and(|other| other.color != piece.color);
+        let is_empty = |sq: Square| self.squares[sq as usize].is_none();
+
+        match piece.kind {
+            PieceKind::Pawn => {
+                let (forward, start_rank) = match piece.color {
+                    Color::White => (1, 1),
+                    Color::Black => (-1, 6),
+                };
+
+                if let Some(one) = offset(from, (0, forward)).filter(|&sq| is_empty(sq)) {
+                    self.push_pawn_move(from, one, MoveKind::Normal, moves);
+
+                    if rank_of(from) == start_rank {
+                        if let Some(two) = offset(one, (0, forward)).filter(|&sq| is_empty(sq)) {
+                            moves.push(normal(two));
+                        }
+                    }
+                }
+
+                for file_step in [-1, 1] {
+                    if let Some(to) = offset(from, (file_step, forward)) {
+                        if is_enemy(to) {
+                            self.push_pawn_move(from, to, MoveKind::Normal, moves);
+                        } else if Some(to) == self.en_passant {
+                            moves.push(Move {
+                                from,
+                                to,
+                                promotion: None,
+                                kind: MoveKind::EnPassant,
+                            });
+                        }
+                    }
+                }
+            }
+            PieceKind::Knight | PieceKind::King => {
+                let steps = if piece.kind == PieceKind::Knight {
+                    &KNIGHT_STEPS
+                } else {
+                    &KING_STEPS
+                };
+
+                for &step in steps.iter() {
+                    if let Some(to) = offset(from, step) {
+                        if is_empty(to) || is_enemy(to) {
+                            moves.push(normal(to));
+                        }
+                    }
+                }
+
+                if piece.kind == PieceKind::King {
+                    for side in [CastlingSide::King, CastlingSide::Queen] {
+                        if let Some(castle) = self.castle(side) {
+                            moves.push(castle);
+                        }
+                    }
+                }
+            }
+            PieceKind::Bishop | PieceKind::Rook | PieceKind::Queen => {
+                let directions: Vec<(i8, i8)> = match piece.kind {
+                    PieceKind::Bishop => BISHOP_DIRECTIONS.to_vec(),
+                    PieceKind::Rook => ROOK_DIRECTIONS.to_vec(),
+                    _ => [ROOK_DIRECTIONS, BISHOP_DIRECTIONS].concat(),
+                };
+
+                for direction in directions {
+                    let mut current = offset(from, direction);
+                    while let Some(to) = current {
+                        if is_empty(to) {
+                            moves.push(normal(to));
+                        } else {
+                            if is_enemy(to) {
+                                moves.push(normal(to));
+                            }
+                            break;
+                        }
+                        current = offset(to, direction);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The castling move for `side`, if it is legal
+    fn castle(&self, side: CastlingSide) -> Option<Move> {
+        let color = self.side_to_move;
+        let rook = self.castling_rooks[color as usize][side as usize]?;
+        let king = self.king_square(color)?;
+        let back_rank = color.back_rank();
+
+        if rank_of(king) != back_rank
+            || self.squares[rook as usize]
+                != Some(Piece {
+                    kind: PieceKind::Rook,
+                    color,
+                })
+        {
+            return None;
+        }
+
+        let (king_to, rook_to) = match side {
+            CastlingSide::King => (square(6, back_rank), square(5, back_rank)),
+            CastlingSide::Queen => (square(2, back_rank), square(3, back_rank)),
+        };
+
+        // Every square either piece crosses must be empty, apart from the king and rook themselves
+        let low = king.min(rook).min(king_to).min(rook_to);
+        let high = king.max(rook).max(king_to).max(rook_to);
+        if (low..=high).any(|sq| sq != king && sq != rook && self.squares[sq as usize].is_some()) {
+            return None;
+        }
+
+        // The king may not castle out of or through check; the destination is
+        // covered by the legality check once the move is played
+        let opponent = color.opponent();
+        if (king.min(king_to)..=king.max(king_to))
+            .any(|sq| sq != king_to && self.is_attacked(sq, opponent))
+        {
+            return None;
+        }
+
+        Some(Move {
+            from: king,
+            to: king_to,
+            promotion: None,
+            kind: MoveKind::Castle { side, rook },
+        })
+    }
+
+    fn is_legal(&self, mv: &Move) -> bool {
+        let mut next = self.clone();
+        next.play(mv);
+        !next.in_check(self.side_to_move)
+    }
+
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let mut moves = vec![];
+        for from in 0..64 {
+            self.pseudo_legal_moves_from(from, &mut moves);
+        }
+        moves.retain(|mv| self.is_legal(mv));
+        moves
+    }
+
+    /// Find the single legal move matching a SAN move, or `None` if there is
+    /// no such move or the SAN is ambiguous. `capture` must match whether the
+    /// move takes a piece.
+    pub fn resolve_san(
+        &self,
+        piece: PieceKind,
+        from_file: Option<u8>,
+        from_rank: Option<u8>,
+        to: Square,
+        capture: bool,
+        promotion: Option<PieceKind>,
+    ) -> Option<Move> {
+        let mut candidates = vec![];
+
+        for from in 0..64 {
+            let matches_from = (from_file.is_none() || from_file == Some(file_of(from)))
+                && (from_rank.is_none() || from_rank == Some(rank_of(from)));
+            let matches_piece = matches!(
+                self.squares[from as usize],
+                Some(p) if p.kind == piece && p.color == self.side_to_move
+            );
+
+            if matches_from && matches_piece {
+                self.pseudo_legal_moves_from(from, &mut candidates);
+            }
+        }
+
+        candidates.retain(|mv| {
+            let takes = mv.kind == MoveKind::EnPassant || self.squares[mv.to as usize].is_some();
+            mv.to == to
+                && takes == capture
+                && mv.promotion == promotion
+                && !matches!(mv.kind, MoveKind::Castle { .. })
+                && self.is_legal(mv)
+        });
+
+        match candidates[..] {
+            [mv] => Some(mv),
+            _ => None,
+        }
+    }
+
+    pub fn resolve_castle(&self, side: CastlingSide) -> Option<Move> {
+        self.castle(side).filter(|mv| self.is_legal(mv))
+    }
+
+    /// Play a move, which must have come from this position
+    pub fn play(&mut self, mv: &Move) {
+        let color = self.side_to_move;
+        let piece = self.squares[mv.from as usize].take();
+
+        match mv.kind {
+            MoveKind::Castle { side, rook } => {
+                let back_rank = color.back_rank();
+                let rook_to = match side {
+                    CastlingSide::King => square(5, back_rank),
+                    CastlingSide::Queen => square(3, back_rank),
+                };
+
+                self.squares[rook as usize] = None;
+                self.squares[rook_to as usize] = Some(Piece {
+                    kind: PieceKind::Rook,
+                    color,
+                });
+                self.squares[mv.to as usize] = piece;
+            }
+            MoveKind::EnPassant => {
+                let captured = square(file_of(mv.to), rank_of(mv.from));
+                self.squares[captured as usize] = None;
+                self.squares[mv.to as usize] = piece;
+            }
+            MoveKind::Normal => {
+                self.squares[mv.to as usize] = match mv.promotion {
+                    Some(kind) => Some(Piece { kind, color }),
+                    None => piece,
+                };
+            }
+        }
+
+        if matches!(piece, Some(p) if p.kind == PieceKind::King) {
+            self.castling_rooks[color as usize] = [None, None];
+        }
+        for rooks in self.castling_rooks.iter_mut() {
+            for rook in rooks.iter_mut() {
+                if *rook == Some(mv.from) || *rook == Some(mv.to) {
+                    *rook = None;
+                }
+            }
+        }
+
+        self.en_passant = match piece {
+            Some(p)
+                if p.kind == PieceKind::Pawn && rank_of(mv.from).abs_diff(rank_of(mv.to)) == 2 =>
+            {
+                Some(square(
+                    file_of(mv.from),
+                    (rank_of(mv.from) + rank_of(mv.to)) / 2,
+                ))
+            }
+            _ => None,
+        };
+
+        self.side_to_move = color.opponent();
+    }
+}
@@ -0,0 +1,584 @@
+// ParsedGame to and from the Game table of the chess_flat_buffer schema.
+//
+// A SAN move is packed into two ushorts. `moves` holds the disambiguation
+// file and rank in bits 0-3 and 4-7 and the destination file and rank in
+// bits 8-11 and 12-15, each 1-based with 0 for none. `move_metadata` holds
+// the piece in bits 0-2, capture 0x8, check 0x10, mate 0x20, the annotation
+// in bits 6-8, the promotion piece in bits 9-11 and a crazyhouse drop 0x1000.
+// Pieces are numbered 1 = P, 2 = N, 3 = B, 4 = R, 5 = Q, 6 = K.
+//
+// Other NAGs go in `nags`, in order, with the index of the move each follows
+// at the same position in `nag_plies`. Comment text is stored the same way in
+// `comments` and `comment_plies`, apart from a game's pre-game `comment`.
+//
+// Opening names are stored once per GameList, in `openings`, and a game's
+// `opening` is 1 + the index of its name there, or 0 for none.
+
+use std::collections::HashMap;
+
+use flatbuffers::{FlatBufferBuilder, ForwardsUOffset, Vector, WIPOffset};
+
+use crate::board::{self, CastlingSide, PieceKind, UciMove};
+use crate::game::{
+    Annotation, Check, Clock, Eval, ParsedGame, ParsedMove, ParsedVariation, SanMove,
+};
+use crate::{
+    Game, GameArgs, GameList, GameListArgs, GameResult, Header, HeaderArgs, Speed, Termination,
+    Title, Variant, Variation, VariationArgs,
+};
+
+const PIECES: [PieceKind; 6] = [
+    PieceKind::Pawn,
+    PieceKind::Knight,
+    PieceKind::Bishop,
+    PieceKind::Rook,
+    PieceKind::Queen,
+    PieceKind::King,
+];
+
+fn piece_code(piece: PieceKind) -> u16 {
+    PIECES.iter().position(|p| *p == piece).unwrap() as u16 + 1
+}
+
+fn piece_from_code(code: u16) -> Option<PieceKind> {
+    PIECES.get((code as usize).checked_sub(1)?).copied()
+}
+
+/// Pack a SAN move into its `moves` and `move_metadata` entries
+pub fn encode_san(san: &SanMove) -> (u16, u16) {
+    let coord = |c: Option<u8>| c.map_or(0, |c| c as u16 + 1);
+
+    let move_data = coord(san.from_file)
+        | coord(san.from_rank) << 4
+        | (board::file_of(san.to) as u16 + 1) << 8
+        | (board::rank_of(san.to) as u16 + 1) << 12;
+
+    let mut metadata = piece_code(san.piece);
+    if san.capture {
+        metadata |= 0x0008;
+    }
+    metadata |= match san.check {
+        None => 0x0000,
+        Some(Check::Check) => 0x0010,
+        Some(Check::Mate) => 0x0020,
+    };
+    if let Some(annotation) = san.annotation {
+        metadata |= (annotation as u16) << 6;
+    }
+    if let Some(promotion) = san.promotion {
+        metadata |= piece_code(promotion) << 9;
+    }
+    if san.drop {
+        metadata |= 0x1000;
+    }
+
+    (move_data, metadata)
+}
+
+/// Unpack a SAN move. Castling is recognised as a king move with a full
+/// disambiguation, which SAN never needs for a king.
+pub fn decode_san(move_data: u16, metadata: u16) -> SanMove {
+    let coord = |c: u16| (c & 0xF).checked_sub(1).map(|c| c as u8);
+
+    let piece = piece_from_code(metadata & 0x7).unwrap_or(PieceKind::Pawn);
+    let from_file = coord(move_data);
+    let from_rank = coord(move_data >> 4);
+    let to = board::square(
+        coord(move_data >> 8).unwrap_or(0),
+        coord(move_data >> 12).unwrap_or(0),
+    );
+
+    let castle = match (piece, from_file, from_rank) {
+        (PieceKind::King, Some(file), Some(_)) if board::file_of(to) > file => {
+            Some(CastlingSide::King)
+        }
+        (PieceKind::King, Some(_), Some(_)) => Some(CastlingSide::Queen),
+        _ => None,
+    };
+
+    SanMove {
+        piece,
+        from_file,
+        from_rank,
+        to,
+        capture: metadata & 0x0008 != 0,
+        promotion: piece_from_code((metadata >> 9) & 0x7),
+        castle,
+        drop: metadata & 0x1000 != 0,
+        check: match metadata & 0x0030 {
+            0x0010 => Some(Check::Check),
+            0x0020 => Some(Check::Mate),
+            _ => None,
+        },
+        annotation: Annotation::from_u8(((metadata >> 6) & 0x7) as u8),
+    }
+}
+
+/// Pack a move UCI-style into 16 bits: the from-square in bits 0-5, the
+/// to-square in bits 6-11 and the promotion piece in bits 12-15, numbered as
+/// in `move_metadata` with 0 for none
+pub fn encode_uci(uci: &UciMove) -> u16 {
+    let promotion = uci.promotion.map_or(0, piece_code);
+
+    uci.from as u16 | (uci.to as u16) << 6 | promotion << 12
+}
+
+pub fn decode_uci(uci: u16) -> UciMove {
+    UciMove {
+        from: (uci & 0x3F) as u8,
+        to: ((uci >> 6) & 0x3F) as u8,
+        promotion: piece_from_code(uci >> 12),
+    }
+}
+
+type Variations<'a> = Vector<'a, ForwardsUOffset<Variation<'a>>>;
+
+/// The `moves`, `move_metadata` and `moves_full` of a line
+fn encode_line(line: &[ParsedMove]) -> (Vec<u16>, Vec<u16>, Vec<u16>) {
+    let mut moves = vec![];
+    let mut move_metadata = vec![];
+    for mv in line.iter() {
+        let (move_data, metadata) = encode_san(&mv.san);
+        moves.push(move_data);
+        move_metadata.push(metadata);
+    }
+    let moves_full = line
+        .iter()
+        .filter_map(|mv| mv.uci.as_ref().map(encode_uci))
+        .collect();
+
+    (moves, move_metadata, moves_full)
+}
+
+/// The `nag_plies` and `nags` of a line
+fn encode_nags(line: &[ParsedMove]) -> (Vec<u16>, Vec<u8>) {
+    line.iter()
+        .enumerate()
+        .flat_map(|(ply, mv)| mv.nags.iter().map(move |&nag| (ply as u16, nag)))
+        .unzip()
+}
+
+/// The `comment_plies` and `comments` of a line
+fn encode_comments(line: &[ParsedMove]) -> (Vec<u16>, Vec<&str>) {
+    line.iter()
+        .enumerate()
+        .filter_map(|(ply, mv)| Some((ply as u16, mv.comment.as_deref()?)))
+        .unzip()
+}
+
+fn create_strings<'a>(
+    builder: &mut FlatBufferBuilder<'a>,
+    strings: &[&str],
+) -> WIPOffset<Vector<'a, ForwardsUOffset<&'a str>>> {
+    let strings: Vec<_> = strings.iter().map(|s| builder.create_string(s)).collect();
+    builder.create_vector(&strings)
+}
+
+/// The variations branching from the moves of `line`, or `None` if there are none
+fn add_variations<'a>(
+    builder: &mut FlatBufferBuilder<'a>,
+    line: &[ParsedMove],
+) -> Option<WIPOffset<Variations<'a>>> {
+    let mut variations = vec![];
+
+    for (ply, mv) in line.iter().enumerate() {
+        for variation in mv.variations.iter() {
+            let line = &variation.moves;
+            let (moves, move_metadata, moves_full) = encode_line(line);
+            let (nag_plies, nags) = encode_nags(line);
+            let (comment_plies, comments) = encode_comments(line);
+            let args = VariationArgs {
+                ply: ply as u16,
+                moves: Some(builder.create_vector(&moves)),
+                move_metadata: Some(builder.create_vector(&move_metadata)),
+                moves_full: Some(builder.create_vector(&moves_full)),
+                nag_plies: Some(builder.create_vector(&nag_plies)),
+                nags: Some(builder.create_vector(&nags)),
+                comment_plies: Some(builder.create_vector(&comment_plies)),
+                comments: Some(create_strings(builder, &comments)),
+                variations: add_variations(builder, line),
+                comment: variation
+                    .comment
+                    .as_deref()
+                    .map(|c| builder.create_string(c)),
+            };
+            variations.push(Variation::create(builder, &args));
+        }
+    }
+
+    if variations.is_empty() {
+        None
+    } else {
+        Some(builder.create_vector(&variations))
+    }
+}
+
+/// Moves from their `moves`, `move_metadata` and `moves_full` entries. The
+/// `moves_full` of a line that was not replayed is empty.
+fn decode_line(
+    moves: Option<Vector<u16>>,
+    metadata: Option<Vector<u16>>,
+    moves_full: Option<Vector<u16>>,
+) -> Vec<ParsedMove> {
+    let metadata: Vec<u16> = metadata.map(|v| v.iter().collect()).unwrap_or_default();
+    let moves_full: Vec<u16> = moves_full.map(|v| v.iter().collect()).unwrap_or_default();
+
+    moves
+        .iter()
+        .flat_map(|v| v.iter())
+        .enumerate()
+        .map(|(ply, move_data)| ParsedMove {
+            san: decode_san(move_data, metadata.get(ply).copied().unwrap_or(0)),
+            uci: moves_full.get(ply).map(|&uci| decode_uci(uci)),
+            clock: None,
+            eval: None,
+            comment: None,
+            nags: vec![],
+            variations: vec![],
+        })
+        .collect()
+}
+
+/// Give each NAG back to the move it follows
+fn attach_nags(line: &mut [ParsedMove], nag_plies: Option<Vector<u16>>, nags: Option<Vector<u8>>) {
+    let nag_plies = nag_plies.iter().flat_map(|v| v.iter());
+    let nags = nags.iter().flat_map(|v| v.iter());
+
+    for (ply, nag) in nag_plies.zip(nags) {
+        if let Some(mv) = line.get_mut(ply as usize) {
+            mv.nags.push(nag);
+        }
+    }
+}
+
+/// Give each comment back to the move it follows
+fn attach_comments(
+    line: &mut [ParsedMove],
+    comment_plies: Option<Vector<u16>>,
+    comments: Option<Vector<ForwardsUOffset<&str>>>,
+) {
+    let comment_plies = comment_plies.iter().flat_map(|v| v.iter());
+    let comments = comments.iter().flat_map(|v| v.iter());
+
+    for (ply, comment) in comment_plies.zip(comments) {
+        if let Some(mv) = line.get_mut(ply as usize) {
+            mv.comment = Some(comment.to_string());
+        }
+    }
+}
+
+/// Give each variation back to the move of `line` it replaces
+fn attach_variations(line: &mut [ParsedMove], variations: Option<Variations>) {
+    for variation in variations.iter().flat_map(|v| v.iter()) {
+        let mut moves = decode_line(
+            variation.moves(),
+            variation.move_metadata(),
+            variation.moves_full(),
+        );
+        attach_nags(&mut moves, variation.nag_plies(), variation.nags());
+        attach_comments(&mut moves, variation.comment_plies(), variation.comments());
+        attach_variations(&mut moves, variation.variations());
+
+        if let Some(mv) = line.get_mut(variation.ply() as usize) {
+            mv.variations.push(ParsedVariation {
+                comment: variation.comment().map(str::to_string),
+                moves,
+            });
+        }
+    }
+}
+
+/// Collects games into a GameList buffer
+pub struct FlatBufferSink<'a> {
+    builder: FlatBufferBuilder<'a>,
+    games: Vec<WIPOffset<Game<'a>>>,
+    /// The opening names of the games added so far, each once
+    openings: Vec<String>,
+    opening_numbers: HashMap<String, u32>,
+}
+
+impl<'a> Default for FlatBufferSink<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> FlatBufferSink<'a> {
+    pub fn new() -> Self {
+        FlatBufferSink {
+            builder: FlatBufferBuilder::with_capacity(1024 * 1024),
+            games: vec![],
+            openings: vec![],
+            opening_numbers: HashMap::new(),
+        }
+    }
+
+    /// The `opening` of a game with this opening name, adding it to `openings`
+    /// if it is new
+    fn opening_number(&mut self, name: &str) -> u32 {
+        if let Some(&number) = self.opening_numbers.get(name) {
+            return number;
+        }
+
+        self.openings.push(name.to_string());
+        let number = self.openings.len() as u32;
+        self.opening_numbers.insert(name.to_string(), number);
+        number
+    }
+
+    /// The number of games added since the last `finish`
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    pub fn add_game(&mut self, game: &ParsedGame) {
+        let opening = game
+            .opening
+            .as_deref()
+            .map_or(0, |name| self.opening_number(name));
+        let builder = &mut self.builder;
+
+        let (moves, move_metadata, moves_full) = encode_line(&game.moves);
+        let (nag_plies, nags) = encode_nags(&game.moves);
+        let (comment_plies, comments) = encode_comments(&game.moves);
+        let headers: Vec<_> = game
+            .headers
+            .iter()
+            .map(|(key, value)| {
+                let args = HeaderArgs {
+                    key: Some(builder.create_string(key)),
+                    value: Some(builder.create_string(value)),
+                };
+                Header::create(builder, &args)
+            })
+            .collect();
+        let clocks: Vec<Clock> = game.moves.iter().filter_map(|mv| mv.clock).collect();
+        let evals: Vec<Eval> = game.moves.iter().filter_map(|mv| mv.eval).collect();
+
+        let clock_hours: Vec<u8> = clocks.iter().map(|c| c.hours).collect();
+        let clock_minutes: Vec<u8> = clocks.iter().map(|c| c.minutes).collect();
+        let clock_seconds: Vec<u8> = clocks.iter().map(|c| c.seconds).collect();
+        let eval_advantage: Vec<f32> = evals
+            .iter()
+            .map(|e| match e {
+                Eval::Pawns(pawns) => *pawns,
+                Eval::MateIn(_) => 0.0,
+            })
+            .collect();
+        let eval_mate_in: Vec<i16> = evals
+            .iter()
+            .map(|e| match e {
+                Eval::Pawns(_) => 0,
+                Eval::MateIn(n) => *n,
+            })
+            .collect();
+
+        let (year, month, day) = game.utc_date.unwrap_or_default();
+        let (eco_category, eco_subcategory) = game.eco.unwrap_or_default();
+        let (time_control_main, time_control_increment) = game.time_control.unwrap_or_default();
+
+        let args = GameArgs {
+            year,
+            month,
+            day,
+            site: game.site.as_deref().map(|s| builder.create_string(s)),
+            white: game.white.as_deref().map(|s| builder.create_string(s)),
+            black: game.black.as_deref().map(|s| builder.create_string(s)),
+            white_rating: game.white_rating.unwrap_or(0),
+            black_rating: game.black_rating.unwrap_or(0),
+            white_diff: game.white_diff,
+            black_diff: game.black_diff,
+            eco_category,
+            eco_subcategory,
+            time_control_main,
+            time_control_increment,
+            result: game.result.map_or(0, |r| r as u8),
+            termination: game.termination.map_or(0, |t| t as u8),
+            moves: Some(builder.create_vector(&moves)),
+            move_metadata: Some(builder.create_vector(&move_metadata)),
+            clock_hours: Some(builder.create_vector(&clock_hours)),
+            clock_minutes: Some(builder.create_vector(&clock_minutes)),
+            clock_seconds: Some(builder.create_vector(&clock_seconds)),
+            eval_available: game.eval_available(),
+            eval_advantage: Some(builder.create_vector(&eval_advantage)),
+            eval_mate_in: Some(builder.create_vector(&eval_mate_in)),
+            moves_full: Some(builder.create_vector(&moves_full)),
+            fen: game.fen.as_deref().map(|s| builder.create_string(s)),
+            variant: game.variant as u8,
+            variations: add_variations(builder, &game.moves),
+            nag_plies: Some(builder.create_vector(&nag_plies)),
+            nags: Some(builder.create_vector(&nags)),
+            comment: game.comment.as_deref().map(|s| builder.create_string(s)),
+            comment_plies: Some(builder.create_vector(&comment_plies)),
+            comments: Some(create_strings(builder, &comments)),
+            headers: Some(builder.create_vector(&headers)),
+            rated: game.rated.map_or(0, |rated| if rated { 2 } else { 1 }),
+            speed: game.speed.map_or(0, |s| s as u8),
+            tournament_id: game
+                .tournament_id
+                .as_deref()
+                .map(|s| builder.create_string(s)),
+            swiss_id: game.swiss_id.as_deref().map(|s| builder.create_string(s)),
+            start_time: game.start_time().unwrap_or(0),
+            white_title: game.white_title.map_or(0, |t| t as u8),
+            black_title: game.black_title.map_or(0, |t| t as u8),
+            opening,
+        };
+
+        let game = Game::create(builder, &args);
+        self.games.push(game);
+    }
+
+    /// Finish a GameList of the games added since the last call
+    pub fn finish(&mut self) -> Vec<u8> {
+        let vectored_games = Some(self.builder.create_vector(&self.games));
+        let openings: Vec<&str> = self.openings.iter().map(String::as_str).collect();
+        let openings = Some(create_strings(&mut self.builder, &openings));
+        let game_list = GameList::create(
+            &mut self.builder,
+            &GameListArgs {
+                games: vectored_games,
+                openings,
+            },
+        );
+
+        self.games = vec![];
+        self.openings = vec![];
+        self.opening_numbers.clear();
+
+        self.builder.finish(game_list, None);
+        let data = self.builder.finished_data().to_vec();
+        self.builder.reset();
+
+        data
+    }
+}
+
+/// Every game of a GameList, with its opening name looked up in the list's `openings`
+pub fn read_game_list(game_list: GameList<'_>) -> Vec<ParsedGame> {
+    let openings: Vec<&str> = game_list
+        .openings()
+        .map(|v| v.iter().collect())
+        .unwrap_or_default();
+
+    game_list
+        .games()
+        .iter()
+        .flat_map(|games| games.iter())
+        .map(|game| {
+            let opening = (game.opening() as usize).checked_sub(1);
+            let mut parsed = ParsedGame::from(game);
+            parsed.opening = opening
+                .and_then(|i| openings.get(i))
+                .map(|name| name.to_string());
+            parsed
+        })
+        .collect()
+}
+
+impl From<Game<'_>> for ParsedGame {
+    /// Clocks and evals are stored without their ply, so they are given back
+    /// to the first moves in order. The opening name is in the GameList, so it
+    /// is left to `read_game_list`.
+    fn from(game: Game<'_>) -> Self {
+        let clock_hours: Vec<u8> = game
+            .clock_hours()
+            .map(|v| v.iter().collect())
+            .unwrap_or_default();
+        let clock_minutes: Vec<u8> = game
+            .clock_minutes()
+            .map(|v| v.iter().collect())
+            .unwrap_or_default();
+        let clock_seconds: Vec<u8> = game
+            .clock_seconds()
+            .map(|v| v.iter().collect())
+            .unwrap_or_default();
+        let eval_advantage: Vec<f32> = game
+            .eval_advantage()
+            .map(|v| v.iter().collect())
+            .unwrap_or_default();
+        let eval_mate_in: Vec<i16> = game
+            .eval_mate_in()
+            .map(|v| v.iter().collect())
+            .unwrap_or_default();
+
+        let mut moves = decode_line(game.moves(), game.move_metadata(), game.moves_full());
+        for (ply, mv) in moves.iter_mut().enumerate() {
+            mv.clock = match (
+                clock_hours.get(ply),
+                clock_minutes.get(ply),
+                clock_seconds.get(ply),
+            ) {
+                (Some(&hours), Some(&minutes), Some(&seconds)) => Some(Clock {
+                    hours,
+                    minutes,
+                    seconds,
+                }),
+                _ => None,
+            };
+            mv.eval = match (eval_advantage.get(ply), eval_mate_in.get(ply)) {
+                _ if !game.eval_available() => None,
+                (Some(_), Some(&mate_in)) if mate_in != 0 => Some(Eval::MateIn(mate_in)),
+                (Some(&pawns), Some(_)) => Some(Eval::Pawns(pawns)),
+                _ => None,
+            };
+        }
+        attach_nags(&mut moves, game.nag_plies(), game.nags());
+        attach_comments(&mut moves, game.comment_plies(), game.comments());
+        attach_variations(&mut moves, game.variations());
+
+        let rating = |rating: u16| Some(rating).filter(|&r| r != 0);
+
+        ParsedGame {
+            site: game.site().map(str::to_string),
+            rated: match game.rated() {
+                1 => Some(false),
+                2 => Some(true),
+                _ => None,
+            },
+            speed: Speed::from_u8(game.speed()),
+            tournament_id: game.tournament_id().map(str::to_string),
+            swiss_id: game.swiss_id().map(str::to_string),
+            white: game.white().map(str::to_string),
+            black: game.black().map(str::to_string),
+            utc_date: Some((game.year(), game.month(), game.day())).filter(|d| d.0 != 0),
+            utc_time: Some(game.start_time()).filter(|&t| t != 0).map(|t| {
+                let seconds = t.rem_euclid(86400);
+                (
+                    (seconds / 3600) as u8,
+                    (seconds / 60 % 60) as u8,
+                    (seconds % 60) as u8,
+                )
+            }),
+            white_rating: rating(game.white_rating()),
+            black_rating: rating(game.black_rating()),
+            white_title: Title::from_u8(game.white_title()),
+            black_title: Title::from_u8(game.black_title()),
+            white_diff: game.white_diff(),
+            black_diff: game.black_diff(),
+            eco: Some((game.eco_category(), game.eco_subcategory())).filter(|e| e.0 != 0),
+            opening: None,
+            time_control: Some((game.time_control_main(), game.time_control_increment()))
+                .filter(|&tc| tc != (0, 0)),
+            result: GameResult::from_u8(game.result()),
+            termination: Termination::from_u8(game.termination()),
+            variant: Variant::from_u8(game.variant()).unwrap_or(Variant::Standard),
+            fen: game.fen().map(str::to_string),
+            headers: game
+                .headers()
+                .iter()
+                .flat_map(|v| v.iter())
+                .map(|h| {
+                    let field = |s: Option<&str>| s.unwrap_or_default().to_string();
+                    (field(h.key()), field(h.value()))
+                })
+                .collect(),
+            comment: game.comment().map(str::to_string),
+            moves,
+        }
+    }
+}
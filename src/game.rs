@@ -0,0 +1,245 @@
+// The parsed form of a game, independent of any output format. The converter
+// produces `ParsedGame`s; `flatbuffer::FlatBufferSink` and `reader::write_pgn`
+// are two ways of writing them out.
+
+use std::fmt;
+
+use crate::board::{self, CastlingSide, PieceKind, Square, UciMove};
+use crate::{GameResult, Speed, Termination, Title, Variant};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParsedGame {
+    pub site: Option<String>,
+    /// From the `Event`: whether the game was rated, `None` if it does not say
+    pub rated: Option<bool>,
+    pub speed: Option<Speed>,
+    /// The lichess arena a game was played in, from the `Event`
+    pub tournament_id: Option<String>,
+    /// The lichess swiss tournament a game was played in, from the `Event`
+    pub swiss_id: Option<String>,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    /// `UTCDate` as year, month and day
+    pub utc_date: Option<(u16, u8, u8)>,
+    /// `UTCTime` as hours, minutes and seconds. Only stored as part of the
+    /// start time, so it is lost on store when there is no `utc_date`.
+    pub utc_time: Option<(u8, u8, u8)>,
+    /// `None` for an unknown (`?`) rating
+    pub white_rating: Option<u16>,
+    pub black_rating: Option<u16>,
+    pub white_title: Option<Title>,
+    pub black_title: Option<Title>,
+    pub white_diff: i16,
+    pub black_diff: i16,
+    /// The ECO category letter and number, e.g. `(b'B', 1)` for B01
+    pub eco: Option<(u8, u8)>,
+    /// The lichess opening name, e.g. "Sicilian Defense: Najdorf Variation"
+    pub opening: Option<String>,
+    /// Base time in seconds and increment, `None` for `-` (no time control)
+    pub time_control: Option<(u16, u8)>,
+    pub result: Option<GameResult>,
+    pub termination: Option<Termination>,
+    pub variant: Variant,
+    pub fen: Option<String>,
+    /// Every other tag that was kept, in the order it was read
+    pub headers: Vec<(String, String)>,
+    /// The text of any comments before the first move
+    pub comment: Option<String>,
+    pub moves: Vec<ParsedMove>,
+}
+
+impl ParsedGame {
+    pub fn eval_available(&self) -> bool {
+        self.moves.iter().any(|mv| mv.eval.is_some())
+    }
+
+    /// Whether either player is a BOT account
+    pub fn has_bot(&self) -> bool {
+        [self.white_title, self.black_title].contains(&Some(Title::BOT))
+    }
+
+    /// When the game started, in seconds since the Unix epoch, if both its
+    /// `UTCDate` and `UTCTime` are known
+    pub fn start_time(&self) -> Option<i64> {
+        let (year, month, day) = self.utc_date?;
+        let (hours, minutes, seconds) = self.utc_time?;
+        let days = days_from_epoch(year as i64, month as i64, day as i64);
+
+        Some(days * 86400 + hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64)
+    }
+}
+
+/// The number of days in a month of the proleptic Gregorian calendar
+pub(crate) fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 => (days_from_epoch(year as i64, 3, 1) - days_from_epoch(year as i64, 2, 1)) as u8,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The number of days from 1970-01-01 to a date in the proleptic Gregorian
+/// calendar, counting in 400-year eras of years starting in March
+fn days_from_epoch(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// A move with the `[%clk]` and `[%eval]` from the comment after it, which
+/// are only read for the mainline, and the rest of that comment's text
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedMove {
+    pub san: SanMove,
+    /// The move as played on the board, when the game could be replayed
+    pub uci: Option<UciMove>,
+    pub clock: Option<Clock>,
+    pub eval: Option<Eval>,
+    /// The text of the comments after the move. `[%...]` commands are taken
+    /// out, so arrows and highlights such as `[%cal]` and `[%csl]` are lost.
+    pub comment: Option<String>,
+    /// The numeric annotation glyphs after the move, other than the one
+    /// stored as its `san.annotation`
+    pub nags: Vec<u8>,
+    /// Alternatives to this move, each a line of moves starting in its place
+    pub variations: Vec<ParsedVariation>,
+}
+
+/// A line of moves played instead of a move, and the text of the comments
+/// before its first move
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedVariation {
+    pub comment: Option<String>,
+    pub moves: Vec<ParsedMove>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Check {
+    Check,
+    Mate,
+}
+
+/// The move suffix annotations, numbered as NAGs $1 to $6. Any other NAG is
+/// kept in `ParsedMove::nags`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Annotation {
+    Good = 1,
+    Mistake = 2,
+    Brilliant = 3,
+    Blunder = 4,
+    Interesting = 5,
+    Dubious = 6,
+}
+
+impl Annotation {
+    const ALL: [Annotation; 6] = [
+        Annotation::Good,
+        Annotation::Mistake,
+        Annotation::Brilliant,
+        Annotation::Blunder,
+        Annotation::Interesting,
+        Annotation::Dubious,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| *a as u8 == value)
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.suffix() == suffix)
+    }
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Annotation::Good => "!",
+            Annotation::Mistake => "?",
+            Annotation::Brilliant => "!!",
+            Annotation::Blunder => "??",
+            Annotation::Interesting => "!?",
+            Annotation::Dubious => "?!",
+        }
+    }
+}
+
+/// A move as written in SAN, before it is matched against the board
+#[derive(Clone, Debug, PartialEq)]
+pub struct SanMove {
+    pub piece: PieceKind,
+    /// The disambiguation, 0-based. A castling move has both, set to the king's square.
+    pub from_file: Option<u8>,
+    pub from_rank: Option<u8>,
+    /// The destination. For castling this is where the king lands, or in
+    /// Chess960 its rook's square.
+    pub to: Square,
+    pub capture: bool,
+    pub promotion: Option<PieceKind>,
+    pub castle: Option<CastlingSide>,
+    /// A crazyhouse drop, e.g. `N@f3`
+    pub drop: bool,
+    pub check: Option<Check>,
+    pub annotation: Option<Annotation>,
+}
+
+impl fmt::Display for SanMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.castle {
+            Some(CastlingSide::King) => write!(f, "O-O")?,
+            Some(CastlingSide::Queen) => write!(f, "O-O-O")?,
+            None if self.drop => {
+                let piece = match self.piece {
+                    PieceKind::Pawn => "P",
+                    piece => piece.to_san(),
+                };
+                write!(f, "{}@{}", piece, board::square_name(self.to))?;
+            }
+            None => {
+                write!(f, "{}", self.piece.to_san())?;
+                if let Some(file) = self.from_file {
+                    write!(f, "{}", (b'a' + file) as char)?;
+                }
+                if let Some(rank) = self.from_rank {
+                    write!(f, "{}", rank + 1)?;
+                }
+                if self.capture {
+                    write!(f, "x")?;
+                }
+                write!(f, "{}", board::square_name(self.to))?;
+                if let Some(promotion) = self.promotion {
+                    write!(f, "={}", promotion.to_san())?;
+                }
+            }
+        }
+
+        match self.check {
+            Some(Check::Check) => write!(f, "+")?,
+            Some(Check::Mate) => write!(f, "#")?,
+            None => {}
+        }
+        if let Some(annotation) = self.annotation {
+            write!(f, "{}", annotation.suffix())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A `[%clk h:mm:ss]` value
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct Clock {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+/// A `[%eval]` value
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub enum Eval {
+    /// The advantage in pawns, from White's point of view
+    Pawns(f32),
+    /// Mate in this many moves, negative when Black mates
+    MateIn(i16),
+}
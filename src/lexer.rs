@@ -0,0 +1,128 @@
+// Splitting movetext into tokens, following the import format of the PGN
+// standard (section 8.2): tokens need no whitespace between them when their
+// first character tells them apart, e.g. `1.e4{[%clk 0:01:00]}(1.d4)$1`.
+
+use crate::GameResult;
+
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub enum Token<'a> {
+    /// A move, or any other text that is not one of the tokens below
+    Symbol(&'a str),
+    /// The text between `{` and `}`, or after `;` to the end of the line
+    Comment(&'a str),
+    /// A numeric annotation glyph, `$` followed by its number
+    Nag(u8),
+    VariationStart,
+    VariationEnd,
+    /// A move number indication, `12.` or `12...`
+    MoveNumber(u32),
+    Result(GameResult),
+}
+
+/// The tokens of a game's movetext, each with the number of the line it starts on
+pub struct Lexer<'a> {
+    text: &'a str,
+    pos: usize,
+    line: u64,
+}
+
+impl<'a> Lexer<'a> {
+    /// Tokenize `text`, whose first line is line `first_line` of the input
+    pub fn new(text: &'a str, first_line: u64) -> Self {
+        Lexer {
+            text,
+            pos: 0,
+            line: first_line,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> &'a str {
+        let taken = &self.text[self.pos..self.pos + len];
+        self.pos += len;
+        self.line += taken.matches('\n').count() as u64;
+        taken
+    }
+
+    /// The length of the longest prefix of the remaining text that is not `stop`
+    fn len_until(&self, stop: impl Fn(char) -> bool) -> usize {
+        let rest = &self.text[self.pos..];
+        rest.find(stop).unwrap_or(rest.len())
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "{};()$".contains(c)
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = (u64, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let whitespace = self.len_until(|c| !c.is_whitespace());
+        self.take(whitespace);
+
+        let line = self.line;
+        let first = self.text[self.pos..].chars().next()?;
+
+        let token = match first {
+            '{' => {
+                // An unterminated comment runs to the end of the movetext
+                let len = self.len_until(|c| c == '}');
+                let comment = &self.take(len)[1..];
+                if self.text[self.pos..].starts_with('}') {
+                    self.take(1);
+                }
+                Token::Comment(comment)
+            }
+            ';' => {
+                let len = self.len_until(|c| c == '\n');
+                Token::Comment(&self.take(len)[1..])
+            }
+            '(' => {
+                self.take(1);
+                Token::VariationStart
+            }
+            ')' => {
+                self.take(1);
+                Token::VariationEnd
+            }
+            '}' => Token::Symbol(self.take(1)),
+            '$' => {
+                let digits = &self.text[self.pos + 1..];
+                let len = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                let nag = self.take(1 + len);
+                match nag[1..].parse() {
+                    Ok(nag) => Token::Nag(nag),
+                    Err(_) => Token::Symbol(nag),
+                }
+            }
+            _ => {
+                let rest = &self.text[self.pos..];
+                let digits = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let dots = rest[digits..]
+                    .find(|c| c != '.')
+                    .unwrap_or(rest.len() - digits);
+
+                if digits > 0 && dots > 0 {
+                    let move_number = self.take(digits + dots);
+                    match move_number[..digits].parse() {
+                        Ok(number) => Token::MoveNumber(number),
+                        Err(_) => Token::Symbol(move_number),
+                    }
+                } else {
+                    let symbol = self.take(self.len_until(is_delimiter));
+                    match GameResult::from_pgn_name(symbol) {
+                        Some(result) => Token::Result(result),
+                        None => Token::Symbol(symbol),
+                    }
+                }
+            }
+        };
+
+        Some((line, token))
+    }
+}
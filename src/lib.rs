@@ -0,0 +1,1369 @@
+//! Parse Lichess PGN exports into `ParsedGame`s, write them out as `GameList`
+//! flatbuffers, and read them back.
+//!
+//! ```
+//! use chess_convert_pgn::Converter;
+//!
+//! let pgn = "[White \"alice\"]\n[Result \"1-0\"]\n\n\
+//!            1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6?? 4. Qxf7# 1-0\n\n";
+//! let converter = Converter::builder().build(pgn.as_bytes()).unwrap();
+//!
+//! for game in converter {
+//!     let game = game.unwrap();
+//!     assert_eq!(game.white.as_deref(), Some("alice"));
+//!     assert_eq!(game.moves.len(), 7);
+//! }
+//! ```
+
+#[macro_use]
+extern crate lazy_static;
+
+use std::fmt;
+use std::io::{self, Read};
+
+use regex::Regex;
+
+pub mod board;
+pub mod flatbuffer;
+pub mod game;
+pub mod lexer;
+pub mod reader;
+
+#[allow(non_snake_case)]
+#[path = "../target/flatbuffers/chess_generated.rs"]
+mod chess;
+
+pub use chess::chess::{
+    Game, GameArgs, GameList, GameListArgs, Header, HeaderArgs, Variation, VariationArgs,
+};
+
+use board::{CastlingSide, MoveKind, PieceKind, Position};
+use game::{Annotation, Check, Clock, Eval, ParsedGame, ParsedMove, ParsedVariation, SanMove};
+use lexer::{Lexer, Token};
+
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub enum GameResult {
+    White = 0,
+    Black = 1,
+    Draw = 2,
+    Star = 255,
+}
+
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub enum Termination {
+    Normal = 0,
+    TimeForfeit = 1,
+    Abandoned = 2,
+    RulesInfraction = 3,
+    Unterminated = 4,
+}
+
+/// The time control category in a lichess `Event`, e.g. "Rated Blitz game"
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Speed {
+    UltraBullet = 1,
+    Bullet = 2,
+    Blitz = 3,
+    Rapid = 4,
+    Classical = 5,
+    Correspondence = 6,
+}
+
+/// A player's title, from `WhiteTitle` or `BlackTitle`
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Title {
+    GM = 1,
+    IM = 2,
+    FM = 3,
+    CM = 4,
+    NM = 5,
+    WGM = 6,
+    WIM = 7,
+    WFM = 8,
+    WCM = 9,
+    WNM = 10,
+    /// Lichess Master, an honorary title lichess gives notable members of its
+    /// community
+    LM = 11,
+    BOT = 12,
+}
+
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub enum Variant {
+    #[default]
+    Standard = 0,
+    Chess960 = 1,
+    Crazyhouse = 2,
+    Antichess = 3,
+    Atomic = 4,
+    Horde = 5,
+    KingOfTheHill = 6,
+    RacingKings = 7,
+    ThreeCheck = 8,
+    FromPosition = 9,
+}
+
+impl GameResult {
+    const ALL: [GameResult; 4] = [
+        GameResult::White,
+        GameResult::Black,
+        GameResult::Draw,
+        GameResult::Star,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| *r as u8 == value)
+    }
+
+    pub fn from_pgn_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.pgn_name() == name)
+    }
+
+    /// The value of the `Result` header and the movetext's game termination marker
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            GameResult::White => "1-0",
+            GameResult::Black => "0-1",
+            GameResult::Draw => "1/2-1/2",
+            GameResult::Star => "*",
+        }
+    }
+}
+
+impl Termination {
+    const ALL: [Termination; 5] = [
+        Termination::Normal,
+        Termination::TimeForfeit,
+        Termination::Abandoned,
+        Termination::RulesInfraction,
+        Termination::Unterminated,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| *t as u8 == value)
+    }
+
+    pub fn from_pgn_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.pgn_name() == name)
+    }
+
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            Termination::Normal => "Normal",
+            Termination::TimeForfeit => "Time forfeit",
+            Termination::Abandoned => "Abandoned",
+            Termination::RulesInfraction => "Rules infraction",
+            Termination::Unterminated => "Unterminated",
+        }
+    }
+}
+
+impl Speed {
+    const ALL: [Speed; 6] = [
+        Speed::UltraBullet,
+        Speed::Bullet,
+        Speed::Blitz,
+        Speed::Rapid,
+        Speed::Classical,
+        Speed::Correspondence,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| *s as u8 == value)
+    }
+
+    pub fn from_pgn_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.pgn_name() == name)
+    }
+
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            Speed::UltraBullet => "UltraBullet",
+            Speed::Bullet => "Bullet",
+            Speed::Blitz => "Blitz",
+            Speed::Rapid => "Rapid",
+            Speed::Classical => "Classical",
+            Speed::Correspondence => "Correspondence",
+        }
+    }
+}
+
+impl Title {
+    const ALL: [Title; 12] = [
+        Title::GM,
+        Title::IM,
+        Title::FM,
+        Title::CM,
+        Title::NM,
+        Title::WGM,
+        Title::WIM,
+        Title::WFM,
+        Title::WCM,
+        Title::WNM,
+        Title::LM,
+        Title::BOT,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| *t as u8 == value)
+    }
+
+    pub fn from_pgn_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.pgn_name() == name)
+    }
+
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            Title::GM => "GM",
+            Title::IM => "IM",
+            Title::FM => "FM",
+            Title::CM => "CM",
+            Title::NM => "NM",
+            Title::WGM => "WGM",
+            Title::WIM => "WIM",
+            Title::WFM => "WFM",
+            Title::WCM => "WCM",
+            Title::WNM => "WNM",
+            Title::LM => "LM",
+            Title::BOT => "BOT",
+        }
+    }
+}
+
+impl Variant {
+    const ALL: [Variant; 10] = [
+        Variant::Standard,
+        Variant::Chess960,
+        Variant::Crazyhouse,
+        Variant::Antichess,
+        Variant::Atomic,
+        Variant::Horde,
+        Variant::KingOfTheHill,
+        Variant::RacingKings,
+        Variant::ThreeCheck,
+        Variant::FromPosition,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| *v as u8 == value)
+    }
+
+    pub fn from_pgn_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|v| v.pgn_name() == name)
+    }
+
+    pub fn pgn_name(self) -> &'static str {
+        match self {
+            Variant::Standard => "Standard",
+            Variant::Chess960 => "Chess960",
+            Variant::Crazyhouse => "Crazyhouse",
+            Variant::Antichess => "Antichess",
+            Variant::Atomic => "Atomic",
+            Variant::Horde => "Horde",
+            Variant::KingOfTheHill => "King of the Hill",
+            Variant::RacingKings => "Racing Kings",
+            Variant::ThreeCheck => "Three-check",
+            Variant::FromPosition => "From Position",
+        }
+    }
+
+    /// Whether moves follow the rules of standard chess, so the game can be
+    /// replayed on a `Position` and `moves_full` filled in. Other variants
+    /// only get the SAN-derived `moves` and `move_metadata`.
+    fn has_standard_moves(self) -> bool {
+        matches!(
+            self,
+            Variant::Standard
+                | Variant::Chess960
+                | Variant::KingOfTheHill
+                | Variant::ThreeCheck
+                | Variant::FromPosition
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    Io(std::io::Error),
+    /// A header whose value could not be parsed; `game` is the 0-based index of
+    /// the game in the input and `line` the 1-based line number of the header
+    InvalidHeader {
+        game: u64,
+        line: u64,
+        header: String,
+        value: String,
+    },
+    /// A movetext token that is neither a move, a move number, a comment nor a result
+    InvalidMove {
+        game: u64,
+        line: u64,
+        token: String,
+    },
+    /// A well-formed move that is illegal or ambiguous when the game is replayed
+    IllegalMove {
+        game: u64,
+        line: u64,
+        token: String,
+    },
+    /// A line that does not fit the header / blank / movetext / blank layout of a game
+    UnexpectedLine {
+        game: u64,
+        line: u64,
+        text: String,
+    },
+    /// The input ended in the middle of a game
+    UnexpectedEof {
+        game: u64,
+        line: u64,
+    },
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::Io(e) => write!(f, "I/O error: {}", e),
+            ConvertError::InvalidHeader {
+                game,
+                line,
+                header,
+                value,
+            } => write!(
+                f,
+                "Game {} (line {}): invalid {} header: {:?}",
+                game, line, header, value
+            ),
+            ConvertError::InvalidMove { game, line, token } => {
+                write!(
+                    f,
+                    "Game {} (line {}): invalid move: {:?}",
+                    game, line, token
+                )
+            }
+            ConvertError::IllegalMove { game, line, token } => {
+                write!(
+                    f,
+                    "Game {} (line {}): illegal move: {:?}",
+                    game, line, token
+                )
+            }
+            ConvertError::UnexpectedLine { game, line, text } => write!(
+                f,
+                "Game {} (line {}): expected a blank line, found {:?}",
+                game, line, text
+            ),
+            ConvertError::UnexpectedEof { game, line } => {
+                write!(f, "Game {} (line {}): unexpected end of input", game, line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<std::io::Error> for ConvertError {
+    fn from(e: std::io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
+// https://stackoverflow.com/questions/45882329/read-large-files-line-by-line-in-rust
+pub mod file_reader {
+    use bzip2::read::MultiBzDecoder;
+    use flate2::read::MultiGzDecoder;
+    use std::{
+        fs::File,
+        io::{self, prelude::*},
+        path::Path,
+    };
+
+    pub(crate) const BZIP2_MAGIC: &[u8] = b"BZh";
+    pub(crate) const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+    pub(crate) const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+
+    #[derive(PartialEq, Clone, Debug, Copy)]
+    pub enum InputCompression {
+        None,
+        Bzip2,
+        Gzip,
+        Zstd,
+    }
+
+    impl InputCompression {
+        // Magic bytes win; the extension is only consulted when the file is too
+        // short to tell (e.g. an empty .zst)
+        pub fn detect(magic: &[u8], path: &Path) -> Self {
+            if magic.starts_with(ZSTD_MAGIC) {
+                return InputCompression::Zstd;
+            }
+            if magic.starts_with(BZIP2_MAGIC) {
+                return InputCompression::Bzip2;
+            }
+            if magic.starts_with(GZIP_MAGIC) {
+                return InputCompression::Gzip;
+            }
+
+            if magic.len() >= ZSTD_MAGIC.len() {
+                return InputCompression::None;
+            }
+
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("zst") => InputCompression::Zstd,
+                Some("bz2") => InputCompression::Bzip2,
+                Some("gz") => InputCompression::Gzip,
+                _ => InputCompression::None,
+            }
+        }
+    }
+
+    /// The input, decompressed as it is read
+    enum Decoded<R: Read> {
+        None(io::BufReader<R>),
+        Bzip2(MultiBzDecoder<io::BufReader<R>>),
+        Gzip(MultiGzDecoder<io::BufReader<R>>),
+        Zstd(zstd::stream::read::Decoder<'static, io::BufReader<R>>),
+    }
+
+    impl<R: Read> Read for Decoded<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                Decoded::None(source) => source.read(buf),
+                Decoded::Bzip2(decoder) => decoder.read(buf),
+                Decoded::Gzip(decoder) => decoder.read(buf),
+                Decoded::Zstd(decoder) => decoder.read(buf),
+            }
+        }
+    }
+
+    /// Reads the lines of PGN from `R`, which may be borrowed, e.g. a `&[u8]`
+    pub struct BufReader<R: Read> {
+        reader: io::BufReader<Decoded<R>>,
+        line_number: u64,
+    }
+
+    impl BufReader<Box<dyn Read + Send>> {
+        /// Open a PGN file, or standard input if `path` is `-`
+        pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+            let path = path.as_ref();
+
+            if path == Path::new("-") {
+                Self::new(Box::new(io::stdin()), path)
+            } else {
+                Self::new(Box::new(File::open(path)?), path)
+            }
+        }
+    }
+
+    impl BufReader<io::Cursor<String>> {
+        /// Read games already split out of the input, where `first_line` is the
+        /// line number of the chunk's first line in the original input
+        pub fn from_chunk(text: String, first_line: u64) -> Self {
+            let source = io::BufReader::new(io::Cursor::new(text));
+            Self {
+                reader: io::BufReader::new(Decoded::None(source)),
+                line_number: first_line.saturating_sub(1),
+            }
+        }
+    }
+
+    impl<R: Read> BufReader<R> {
+        /// Read PGN from any reader, detecting compression from its first bytes
+        pub fn from_reader(inner: R) -> io::Result<Self> {
+            Self::new(inner, Path::new(""))
+        }
+
+        fn new(inner: R, path: &Path) -> io::Result<Self> {
+            let mut source = io::BufReader::new(inner);
+            let compression = InputCompression::detect(source.fill_buf()?, path);
+
+            let decoded = match compression {
+                InputCompression::None => Decoded::None(source),
+                InputCompression::Bzip2 => Decoded::Bzip2(MultiBzDecoder::new(source)),
+                InputCompression::Gzip => Decoded::Gzip(MultiGzDecoder::new(source)),
+                InputCompression::Zstd => {
+                    Decoded::Zstd(zstd::stream::read::Decoder::with_buffer(source)?)
+                }
+            };
+            let reader = io::BufReader::new(decoded);
+
+            Ok(Self {
+                reader,
+                line_number: 0,
+            })
+        }
+
+        /// The 1-based number of the line most recently read
+        pub fn line_number(&self) -> u64 {
+            self.line_number
+        }
+
+        pub fn read_line<'buf>(
+            &mut self,
+            buffer: &'buf mut String,
+        ) -> Option<io::Result<&'buf mut String>> {
+            buffer.clear();
+
+            let line_number = &mut self.line_number;
+
+            self.reader
+                .read_line(buffer)
+                .map(|u| {
+                    if u == 0 {
+                        None
+                    } else {
+                        *line_number += 1;
+                        Some(buffer)
+                    }
+                })
+                .transpose()
+        }
+    }
+}
+
+/// Whether a line is a tag pair, e.g. `[Event "Rated Blitz game"]`
+fn is_header(line: &str) -> bool {
+    line.len() > 1 && line.starts_with('[')
+}
+
+/// Whether a line of movetext ends with a game termination marker. The
+/// marker may be part of a comment, which only the lexer can tell.
+pub fn ends_with_termination_marker(line: &str) -> bool {
+    ["1-0", "0-1", "1/2-1/2", "*"].iter().any(|marker| {
+        line.strip_suffix(marker).is_some_and(|rest| {
+            rest.is_empty() || rest.ends_with(|c: char| c.is_whitespace() || c == '}' || c == ')')
+        })
+    })
+}
+
+/// Which of the tags without a field of their own are kept in `ParsedGame::headers`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HeaderFilter {
+    #[default]
+    All,
+    /// Only the tags listed
+    Only(Vec<String>),
+    /// Every tag but the ones listed
+    Except(Vec<String>),
+}
+
+impl HeaderFilter {
+    pub fn keeps(&self, tag: &str) -> bool {
+        match self {
+            HeaderFilter::All => true,
+            HeaderFilter::Only(tags) => tags.iter().any(|t| t == tag),
+            HeaderFilter::Except(tags) => !tags.iter().any(|t| t == tag),
+        }
+    }
+}
+
+/// Builds a `Converter` over any source of PGN text
+#[derive(Clone, Debug, Default)]
+pub struct ConverterBuilder {
+    first_game: u64,
+    headers: HeaderFilter,
+    skip_bots: bool,
+}
+
+impl ConverterBuilder {
+    /// The number of the first game in the input, used in errors when the input
+    /// is part of a larger file. Defaults to 0.
+    pub fn first_game(mut self, index: u64) -> Self {
+        self.first_game = index;
+        self
+    }
+
+    /// Which other tags to keep alongside the ones stored in fields of their
+    /// own. Defaults to `HeaderFilter::All`.
+    pub fn headers(mut self, filter: HeaderFilter) -> Self {
+        self.headers = filter;
+        self
+    }
+
+    /// Leave out games played by a BOT account. Defaults to false.
+    pub fn skip_bots(mut self, skip_bots: bool) -> Self {
+        self.skip_bots = skip_bots;
+        self
+    }
+
+    /// A converter reading PGN from `input`, which may be plain text or
+    /// compressed with bzip2, gzip or zstd
+    pub fn build<R: Read>(self, input: R) -> io::Result<Converter<R>> {
+        Ok(self.build_from(file_reader::BufReader::from_reader(input)?))
+    }
+
+    /// A converter reading PGN from an already opened `file_reader::BufReader`
+    pub fn build_from<R: Read>(self, reader: file_reader::BufReader<R>) -> Converter<R> {
+        Converter::new(reader, self.first_game, self.headers, self.skip_bots)
+    }
+}
+
+/// Why a SAN move could not be played
+enum MoveError {
+    /// The token is not a move
+    Invalid,
+    /// The move is illegal or ambiguous in the position
+    Illegal,
+}
+
+/// Add comment text to any already read for the same place, separated by a space
+fn append_comment(comment: &mut Option<String>, text: String) {
+    if text.is_empty() {
+        return;
+    }
+    match comment {
+        Some(comment) => {
+            comment.push(' ');
+            comment.push_str(&text);
+        }
+        None => *comment = Some(text),
+    }
+}
+
+/// A line of play being read: the mainline or a variation
+struct Line {
+    /// The text of the comments before the first move of a variation
+    comment: Option<String>,
+    moves: Vec<ParsedMove>,
+    /// The position after the last move and its side to move, which is only
+    /// kept up to date by the board when the variant is replayed
+    position: Position,
+    side: board::Color,
+    /// The position before the last move, where a variation on it starts
+    previous: Option<(Position, board::Color)>,
+}
+
+impl Line {
+    fn new(position: Position) -> Self {
+        let side = position.side_to_move();
+        Line::new_at((position, side))
+    }
+
+    fn new_at((position, side): (Position, board::Color)) -> Self {
+        Line {
+            comment: None,
+            moves: vec![],
+            position,
+            side,
+            previous: None,
+        }
+    }
+
+    fn add_variation(&mut self, variation: Line) {
+        // A line only has variations opened once it has a move
+        let last = self.moves.last_mut().unwrap();
+        last.variations.push(ParsedVariation {
+            comment: variation.comment,
+            moves: variation.moves,
+        });
+    }
+
+    /// Parse a SAN move and, if `replay`, play it on the board
+    fn play_san(&mut self, token: &str, replay: bool, chess960: bool) -> Result<(), MoveError> {
+        lazy_static! {
+            static ref RE_MOVE: Regex = Regex::new(
+                r#"^([NBRQK]?)([a-h1-9]{0,4})(x?)([a-h1-9]{2})(=?)([NBRQK]?)([+#]?)([?!]{0,2})$"#
+            )
+            .unwrap();
+            static ref RE_CASTLING: Regex = Regex::new(r#"^(O-O-?O?)([+#]?)([?!]{0,2})$"#).unwrap();
+            static ref RE_DROP: Regex =
+                Regex::new(r#"^([PNBRQ]?)@([a-h][1-8])([+#]?)([?!]{0,2})$"#).unwrap();
+        }
+
+        let check = |check_str: &str| match check_str {
+            "+" => Some(Check::Check),
+            "#" => Some(Check::Mate),
+            _ => None,
+        };
+        let previous = (self.position.clone(), self.side);
+
+        let san;
+        let mut uci = None;
+
+        if let Some(cap) = RE_CASTLING.captures(token) {
+            let castling_side = if cap[1].len() == 3 {
+                CastlingSide::King
+            } else {
+                CastlingSide::Queen
+            };
+            let back_rank = if self.side == board::Color::White {
+                0
+            } else {
+                7
+            };
+
+            // In Chess960 the king and rook can start on any file, so
+            // castling is recorded as the king moving to the rook's square
+            let (king_from, king_dest) = if replay {
+                let resolved = self
+                    .position
+                    .resolve_castle(castling_side)
+                    .ok_or(MoveError::Illegal)?;
+                self.position.play(&resolved);
+                uci = Some(resolved.uci(chess960));
+
+                match resolved.kind {
+                    MoveKind::Castle { rook, .. } if chess960 => (resolved.from, rook),
+                    _ => (resolved.from, resolved.to),
+                }
+            } else {
+                let king_file = match castling_side {
+                    CastlingSide::King => 6,
+                    CastlingSide::Queen => 2,
+                };
+                (
+                    board::square(4, back_rank),
+                    board::square(king_file, back_rank),
+                )
+            };
+
+            san = SanMove {
+                piece: PieceKind::King,
+                from_file: Some(board::file_of(king_from)),
+                from_rank: Some(board::rank_of(king_from)),
+                to: king_dest,
+                capture: false,
+                promotion: None,
+                castle: Some(castling_side),
+                drop: false,
+                check: check(&cap[2]),
+                annotation: Annotation::from_suffix(&cap[3]),
+            };
+        } else if let Some(cap) = RE_MOVE.captures(token) {
+            let disambiguation_str = &cap[2];
+            let dest_str = &cap[4];
+            if disambiguation_str.len() > dest_str.len() {
+                return Err(MoveError::Invalid);
+            }
+            if cap[5].len() != cap[6].len() {
+                return Err(MoveError::Invalid);
+            }
+
+            let piece = PieceKind::from_san(&cap[1]).ok_or(MoveError::Invalid)?;
+            let (from_file, from_rank) =
+                board::parse_disambiguation(disambiguation_str).ok_or(MoveError::Invalid)?;
+            let to = board::parse_square(dest_str).ok_or(MoveError::Invalid)?;
+            let capture = &cap[3] == "x";
+            let promotion = match &cap[6] {
+                "" => None,
+                p => Some(PieceKind::from_san(p).ok_or(MoveError::Invalid)?),
+            };
+
+            if replay {
+                let resolved = self
+                    .position
+                    .resolve_san(piece, from_file, from_rank, to, capture, promotion)
+                    .ok_or(MoveError::Illegal)?;
+                self.position.play(&resolved);
+                uci = Some(resolved.uci(chess960));
+            }
+
+            san = SanMove {
+                piece,
+                from_file,
+                from_rank,
+                to,
+                capture,
+                promotion,
+                castle: None,
+                drop: false,
+                check: check(&cap[7]),
+                annotation: Annotation::from_suffix(&cap[8]),
+            };
+        } else if let Some(cap) = RE_DROP.captures(token) {
+            // Drops are never legal in the variants that get replayed
+            if replay {
+                return Err(MoveError::Illegal);
+            }
+
+            san = SanMove {
+                piece: PieceKind::from_san(&cap[1]).ok_or(MoveError::Invalid)?,
+                from_file: None,
+                from_rank: None,
+                to: board::parse_square(&cap[2]).ok_or(MoveError::Invalid)?,
+                capture: false,
+                promotion: None,
+                castle: None,
+                drop: true,
+                check: check(&cap[3]),
+                annotation: Annotation::from_suffix(&cap[4]),
+            };
+        } else {
+            return Err(MoveError::Invalid);
+        }
+
+        self.side = self.side.opponent();
+        self.previous = Some(previous);
+        self.moves.push(ParsedMove {
+            san,
+            uci,
+            clock: None,
+            eval: None,
+            comment: None,
+            nags: vec![],
+            variations: vec![],
+        });
+
+        Ok(())
+    }
+}
+
+/// Converts PGN games into `ParsedGame`s.
+///
+/// Iterating over a converter yields each game in turn. A game that fails to
+/// convert is still read to its end, so after an error other than
+/// `ConvertError::Io` the converter is positioned at the next game, `raw_game`
+/// holds the text of the failed game and iteration can carry on.
+pub struct Converter<R: Read> {
+    reader: file_reader::BufReader<R>,
+    game_index: u64,
+    headers: HeaderFilter,
+    skip_bots: bool,
+    raw_game: String,
+    /// A line already read that starts the next game
+    next_game_line: Option<String>,
+    /// The line of the current game's FEN header, parsed only if the game is replayed
+    fen_line: u64,
+    game: ParsedGame,
+}
+
+// On one concrete reader type, so `Converter::builder()` needs no annotation
+impl Converter<&[u8]> {
+    pub fn builder() -> ConverterBuilder {
+        ConverterBuilder::default()
+    }
+}
+
+impl<R: Read> Converter<R> {
+    /// A converter whose first game is game number `game_index` of the input
+    fn new(
+        reader: file_reader::BufReader<R>,
+        game_index: u64,
+        headers: HeaderFilter,
+        skip_bots: bool,
+    ) -> Self {
+        Converter {
+            reader,
+            game_index,
+            headers,
+            skip_bots,
+            raw_game: String::new(),
+            next_game_line: None,
+            fen_line: 0,
+            game: ParsedGame::default(),
+        }
+    }
+
+    fn read_header(&mut self, line: &str) -> Result<(), ConvertError> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r#"\[(.*) "(.*)"\]"#).unwrap();
+        }
+
+        let game = self.game_index;
+        let line_number = self.reader.line_number();
+
+        for cap in RE.captures_iter(line) {
+            let field = &cap[1];
+            let value = &cap[2];
+
+            let invalid = || ConvertError::InvalidHeader {
+                game,
+                line: line_number,
+                header: field.to_string(),
+                value: value.to_string(),
+            };
+
+            match field {
+                "Event" => {
+                    self.read_event(value);
+                    self.keep_header(field, value);
+                }
+                "UTCDate" => {
+                    let date_parts: Vec<&str> = value.split('.').collect();
+                    if date_parts.len() != 3 {
+                        return Err(invalid());
+                    }
+
+                    let year = date_parts[0].parse::<u16>().map_err(|_| invalid())?;
+                    let month = date_parts[1].parse::<u8>().map_err(|_| invalid())?;
+                    let day = date_parts[2].parse::<u8>().map_err(|_| invalid())?;
+                    if !(1..=12).contains(&month)
+                        || !(1..=game::days_in_month(year, month)).contains(&day)
+                    {
+                        return Err(invalid());
+                    }
+
+                    self.game.utc_date = Some((year, month, day));
+                }
+                "UTCTime" => {
+                    let time_parts: Vec<&str> = value.split(':').collect();
+                    if time_parts.len() != 3 {
+                        return Err(invalid());
+                    }
+
+                    let hours = time_parts[0].parse::<u8>().map_err(|_| invalid())?;
+                    let minutes = time_parts[1].parse::<u8>().map_err(|_| invalid())?;
+                    let seconds = time_parts[2].parse::<u8>().map_err(|_| invalid())?;
+                    if hours > 23 || minutes > 59 || seconds > 59 {
+                        return Err(invalid());
+                    }
+
+                    self.game.utc_time = Some((hours, minutes, seconds));
+                }
+                "TimeControl" => {
+                    if value == "-" {
+                        self.game.time_control = None;
+                    } else {
+                        let time_control_parts: Vec<&str> = value.split('+').collect();
+                        if time_control_parts.len() != 2 {
+                            return Err(invalid());
+                        }
+
+                        self.game.time_control = Some((
+                            time_control_parts[0]
+                                .parse::<u16>()
+                                .map_err(|_| invalid())?,
+                            time_control_parts[1].parse::<u8>().map_err(|_| invalid())?,
+                        ));
+                    }
+                }
+                "WhiteElo" => {
+                    if value == "?" {
+                        self.game.white_rating = None;
+                    } else {
+                        self.game.white_rating = Some(value.parse::<u16>().map_err(|_| invalid())?);
+                    }
+                }
+                "BlackElo" => {
+                    if value == "?" {
+                        self.game.black_rating = None;
+                    } else {
+                        self.game.black_rating = Some(value.parse::<u16>().map_err(|_| invalid())?);
+                    }
+                }
+                // A title lichess does not award is kept only as a header
+                "WhiteTitle" => match Title::from_pgn_name(value) {
+                    Some(title) => self.game.white_title = Some(title),
+                    None => self.keep_header(field, value),
+                },
+                "BlackTitle" => match Title::from_pgn_name(value) {
+                    Some(title) => self.game.black_title = Some(title),
+                    None => self.keep_header(field, value),
+                },
+                "WhiteRatingDiff" => {
+                    self.game.white_diff = value.parse::<i16>().map_err(|_| invalid())?;
+                }
+                "BlackRatingDiff" => {
+                    self.game.black_diff = value.parse::<i16>().map_err(|_| invalid())?;
+                }
+                "ECO" => {
+                    if value == "?" {
+                        self.game.eco = None;
+                    } else {
+                        let mut chars = value.chars();
+
+                        match chars.next() {
+                            Some(cat_char) if cat_char.is_ascii_alphabetic() => {
+                                let subcategory =
+                                    chars.as_str().parse::<u8>().map_err(|_| invalid())?;
+                                self.game.eco = Some((cat_char as u8, subcategory));
+                            }
+                            _ => return Err(invalid()),
+                        }
+                    }
+                }
+                "Result" => {
+                    self.game.result = Some(GameResult::from_pgn_name(value).ok_or_else(invalid)?);
+                }
+                "Termination" => {
+                    self.game.termination =
+                        Some(Termination::from_pgn_name(value).ok_or_else(invalid)?);
+                }
+                "SetUp" if value != "0" && value != "1" => {
+                    return Err(invalid());
+                }
+                "Variant" => {
+                    self.game.variant = Variant::from_pgn_name(value).ok_or_else(invalid)?;
+                }
+                "FEN" => {
+                    self.fen_line = line_number;
+                    self.game.fen = Some(value.to_string());
+                }
+                "Site" => {
+                    self.game.site = Some(value.to_string());
+                }
+                "Opening" => {
+                    self.game.opening = Some(value.to_string());
+                }
+                "White" => {
+                    self.game.white = Some(value.to_string());
+                }
+                "Black" => {
+                    self.game.black = Some(value.to_string());
+                }
+                // Written back from the FEN
+                "SetUp" => {}
+                _ => self.keep_header(field, value),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store a tag without a field of its own, if the header filter keeps it
+    fn keep_header(&mut self, tag: &str, value: &str) {
+        if self.headers.keeps(tag) {
+            self.game.headers.push((tag.to_string(), value.to_string()));
+        }
+    }
+
+    /// Read what lichess puts in `Event`: "Rated Blitz game", or for a game in
+    /// an arena or swiss, "Casual Bullet tournament https://lichess.org/tournament/{id}".
+    /// Any other event is kept only as a header.
+    fn read_event(&mut self, event: &str) {
+        lazy_static! {
+            static ref RE_TOURNAMENT: Regex =
+                Regex::new(r#"lichess\.org/(tournament|swiss)/(\w+)"#).unwrap();
+        }
+
+        let mut words = event.split_whitespace();
+        self.game.rated = match words.next() {
+            Some("Rated") => Some(true),
+            Some("Casual") => Some(false),
+            _ => None,
+        };
+        self.game.speed = words.find_map(Speed::from_pgn_name);
+
+        if let Some(cap) = RE_TOURNAMENT.captures(event) {
+            let id = Some(cap[2].to_string());
+            match &cap[1] {
+                "tournament" => self.game.tournament_id = id,
+                _ => self.game.swiss_id = id,
+            }
+        }
+    }
+
+    /// Parse the movetext, whose first line is line `first_line` of the input.
+    /// Clocks and evals are read from the comments after mainline moves. Every
+    /// `[%...]` command, including the `[%cal]` arrows and `[%csl]` highlights,
+    /// is removed from the comment text that is kept.
+    fn parse_game_text(&mut self, movetext: &str, first_line: u64) -> Result<(), ConvertError> {
+        lazy_static! {
+            static ref RE_EVAL: Regex =
+                Regex::new(r#"\[%eval\s+(?:#(-?\d+)|(-?\d+(?:\.\d+)?))"#).unwrap();
+            static ref RE_CLK: Regex = Regex::new(r#"\[%clk\s+(\d+):(\d{2}):(\d{2})"#).unwrap();
+            static ref RE_COMMAND: Regex = Regex::new(r#"\[%[^\]]*\]"#).unwrap();
+        }
+
+        let game = self.game_index;
+        let replay = self.game.variant.has_standard_moves();
+        let chess960 = self.game.variant == Variant::Chess960;
+
+        // Variants that are not replayed may have FENs a Position can't hold,
+        // like the promoted pieces (`Q~`) of crazyhouse
+        let initial_position = match &self.game.fen {
+            Some(fen) if replay => {
+                Position::from_fen(fen).ok_or_else(|| ConvertError::InvalidHeader {
+                    game,
+                    line: self.fen_line,
+                    header: "FEN".to_string(),
+                    value: fen.clone(),
+                })?
+            }
+            _ => Position::starting(),
+        };
+
+        // The mainline, followed by the variations being read, innermost last
+        let mut lines = vec![Line::new(initial_position)];
+
+        for (line_number, token) in Lexer::new(movetext, first_line) {
+            let invalid_move = |token: &str| ConvertError::InvalidMove {
+                game,
+                line: line_number,
+                token: token.to_string(),
+            };
+            let illegal_move = |token: &str| ConvertError::IllegalMove {
+                game,
+                line: line_number,
+                token: token.to_string(),
+            };
+
+            let in_mainline = lines.len() == 1;
+            let line = lines.last_mut().unwrap();
+
+            match token {
+                Token::VariationStart => {
+                    // A variation replaces the last move of the enclosing line
+                    let start = line.previous.clone().ok_or_else(|| invalid_move("("))?;
+                    lines.push(Line::new_at(start));
+                }
+                Token::VariationEnd => {
+                    if in_mainline {
+                        return Err(invalid_move(")"));
+                    }
+                    let variation = lines.pop().unwrap();
+                    lines.last_mut().unwrap().add_variation(variation);
+                }
+                Token::MoveNumber(_) | Token::Result(_) => {}
+                Token::Nag(nag) => {
+                    // $1 to $6 are the move suffix annotations; a suffix
+                    // written on the move itself takes precedence
+                    if let Some(last) = line.moves.last_mut() {
+                        match Annotation::from_u8(nag) {
+                            Some(annotation) if last.san.annotation.is_none() => {
+                                last.san.annotation = Some(annotation)
+                            }
+                            _ => last.nags.push(nag),
+                        }
+                    }
+                }
+                Token::Comment(comment) => {
+                    let text = RE_COMMAND.replace_all(comment, " ");
+                    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+                    // A comment belongs to the move it follows, or to the game
+                    // or variation when it comes before the first move
+                    let last = match line.moves.last_mut() {
+                        Some(last) => last,
+                        None if in_mainline => {
+                            append_comment(&mut self.game.comment, text);
+                            continue;
+                        }
+                        None => {
+                            append_comment(&mut line.comment, text);
+                            continue;
+                        }
+                    };
+                    append_comment(&mut last.comment, text);
+
+                    // Clocks and evals are only read for the mainline
+                    if !in_mainline {
+                        continue;
+                    }
+
+                    if let Some(cap) = RE_EVAL.captures(comment) {
+                        if let Some(mate_in) = cap.get(1) {
+                            if let Ok(mate_in) = mate_in.as_str().parse::<i16>() {
+                                last.eval = Some(Eval::MateIn(mate_in));
+                            }
+                        } else {
+                            last.eval = Some(Eval::Pawns(cap[2].parse::<f32>().unwrap()));
+                        }
+                    }
+
+                    if let Some(cap) = RE_CLK.captures(comment) {
+                        if let (Ok(hours), Ok(minutes), Ok(seconds)) =
+                            (cap[1].parse(), cap[2].parse(), cap[3].parse())
+                        {
+                            last.clock = Some(Clock {
+                                hours,
+                                minutes,
+                                seconds,
+                            });
+                        }
+                    }
+                }
+                Token::Symbol(token) => {
+                    line.play_san(token, replay, chess960)
+                        .map_err(|e| match e {
+                            MoveError::Invalid => invalid_move(token),
+                            MoveError::Illegal => illegal_move(token),
+                        })?;
+                }
+            }
+        }
+
+        // Variations still open at the end of the movetext end there
+        while lines.len() > 1 {
+            let variation = lines.pop().unwrap();
+            lines.last_mut().unwrap().add_variation(variation);
+        }
+        self.game.moves = lines.pop().unwrap().moves;
+
+        Ok(())
+    }
+
+    fn next_line<'buf>(
+        &mut self,
+        buffer: &'buf mut String,
+    ) -> Option<std::io::Result<&'buf mut String>> {
+        if let Some(line) = self.next_game_line.take() {
+            *buffer = line;
+            self.raw_game.push_str(buffer);
+            return Some(Ok(buffer));
+        }
+
+        let res = self.reader.read_line(buffer);
+
+        if let Some(Ok(line)) = &res {
+            self.raw_game.push_str(line);
+        }
+
+        res
+    }
+
+    /// Give back the line just read, which starts the next game, so that the
+    /// following call to `read_game` reads it again
+    fn unread_line(&mut self, line: &str) {
+        self.raw_game.truncate(self.raw_game.len() - line.len());
+        self.next_game_line = Some(line.to_string());
+    }
+
+    /// Consume lines up to and including the next blank line so that the
+    /// following call to `read_game` starts at the next game
+    fn skip_to_blank_line(&mut self, buffer: &mut String) -> std::io::Result<()> {
+        while let Some(line) = self.next_line(buffer) {
+            if line?.trim().is_empty() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the next game in the input, returning `Ok(None)` at the end of the input
+    fn read_game(&mut self) -> Result<Option<ParsedGame>, ConvertError> {
+        let mut buffer = String::new();
+        let mut header_error = None;
+        // Whether any line of the game has been read, so a blank line ends its headers
+        let mut in_game = false;
+
+        self.raw_game.clear();
+        self.game = ParsedGame::default();
+
+        loop {
+            let res = self.next_line(&mut buffer);
+
+            match res {
+                None if in_game => {
+                    let e = ConvertError::UnexpectedEof {
+                        game: self.game_index,
+                        line: self.reader.line_number(),
+                    };
+                    self.game_index += 1;
+                    return Err(e);
+                }
+                None => return Ok(None),
+                Some(line) => {
+                    let trimmed = line?.trim();
+                    if trimmed.starts_with('%') {
+                        continue;
+                    } else if is_header(trimmed) {
+                        in_game = true;
+                        if let Err(e) = self.read_header(trimmed) {
+                            header_error.get_or_insert(e);
+                        }
+                    } else if trimmed.is_empty() && !in_game {
+                        // Extra blank lines between games are not part of either
+                        self.raw_game.clear();
+                    } else if trimmed.is_empty() {
+                        break;
+                    } else {
+                        // The rest of the game is still read, so that the next
+                        // game starts where it should
+                        in_game = true;
+                        if header_error.is_none() {
+                            header_error = Some(self.unexpected_line(trimmed));
+                        }
+                    }
+                }
+            }
+        }
+
+        // The movetext may be wrapped over several lines. It runs up to the
+        // game termination marker, or failing that a blank line.
+        let mut movetext = String::new();
+        let mut first_line = 0;
+        let mut terminated = false;
+
+        while !terminated {
+            let line = match self.next_line(&mut buffer) {
+                Some(v) => v?,
+                None if first_line == 0 => {
+                    let e = ConvertError::UnexpectedEof {
+                        game: self.game_index,
+                        line: self.reader.line_number(),
+                    };
+                    self.game_index += 1;
+                    return Err(e);
+                }
+                None => break,
+            };
+
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                break;
+            } else if trimmed.starts_with('%') {
+                continue;
+            } else if first_line == 0 && is_header(trimmed) {
+                // The game has no movetext, and this header starts the next one
+                header_error.get_or_insert(self.unexpected_line(trimmed));
+                let line = line.clone();
+                self.unread_line(&line);
+                break;
+            }
+
+            if first_line == 0 {
+                first_line = self.reader.line_number();
+            } else {
+                movetext.push('\n');
+            }
+            movetext.push_str(trimmed);
+            // A marker at the end of the line may still be inside a comment
+            terminated = ends_with_termination_marker(trimmed)
+                && matches!(
+                    Lexer::new(&movetext, first_line).last(),
+                    Some((_, Token::Result(_)))
+                );
+        }
+
+        let mut game_error = match header_error {
+            Some(e) => Err(e),
+            None => self.parse_game_text(&movetext, first_line),
+        };
+
+        if terminated {
+            if let Some(line) = self.next_line(&mut buffer) {
+                let line = line?;
+                let trimmed = line.trim();
+                if is_header(trimmed) {
+                    // The next game follows without a blank line in between
+                    let line = line.clone();
+                    self.unread_line(&line);
+                } else if !trimmed.is_empty() {
+                    let e = self.unexpected_line(trimmed);
+                    self.skip_to_blank_line(&mut buffer)?;
+                    game_error = game_error.and(Err(e));
+                }
+            }
+        }
+
+        self.game_index += 1;
+        game_error?;
+
+        Ok(Some(std::mem::take(&mut self.game)))
+    }
+
+    fn unexpected_line(&self, text: &str) -> ConvertError {
+        ConvertError::UnexpectedLine {
+            game: self.game_index,
+            line: self.reader.line_number(),
+            text: text.to_string(),
+        }
+    }
+
+    /// The raw text of the game most recently read
+    pub fn raw_game(&self) -> &str {
+        &self.raw_game
+    }
+}
+
+impl<R: Read> Iterator for Converter<R> {
+    type Item = Result<ParsedGame, ConvertError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.read_game() {
+                Ok(Some(game)) if self.skip_bots && game.has_bot() => continue,
+                game => return game.transpose(),
+            }
+        }
+    }
+}
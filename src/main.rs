@@ -1,558 +1,219 @@
-#[macro_use]
-extern crate lazy_static;
+use clap::{Arg, Command, ErrorKind};
+use std::io::{self, Write};
 
-use clap::{Arg, Command};
-use std::fs::File;
-use std::io::prelude::*;
+use chess_convert_pgn::{file_reader, reader, ConvertError, Converter, HeaderFilter};
 
-use bzip2::write::BzEncoder;
-use bzip2::Compression;
-use flatbuffers::{FlatBufferBuilder, WIPOffset};
-use regex::Regex;
-
-#[allow(non_snake_case)]
-#[path = "../target/flatbuffers/chess_generated.rs"]
-mod chess;
-
-pub use chess::chess::{
-    Game, GameArgs, GameList, GameListArgs
-};
+mod pipeline;
 
+/// What to do with a game that fails to convert
 #[derive(PartialEq, Clone, Debug, Copy)]
-pub enum GameResult {
-    White = 0,
-    Black = 1,
-    Draw = 2,
-    Star = 255,
+pub enum ErrorPolicy {
+    /// Drop the game silently
+    Skip,
+    /// Drop the game and report it on stderr
+    Log,
+    /// Stop the conversion
+    Abort,
+    /// Drop the game and copy its raw text to `{prefix}_rejects.pgn`
+    Quarantine,
 }
 
-#[derive(PartialEq, Clone, Debug, Copy)]
-pub enum Termination {
-    Normal = 0,
-    TimeForfeit = 1,
-    Abandoned = 2,
-    RulesInfraction = 3,
-    Unterminated = 4,
-}
-
-// https://stackoverflow.com/questions/45882329/read-large-files-line-by-line-in-rust
-mod file_reader {
+mod output_sink {
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
     use std::{
         fs::File,
         io::{self, prelude::*},
     };
-
-    pub struct BufReader {
-        reader: io::BufReader<File>,
+    use xz2::write::XzEncoder;
+
+    #[derive(PartialEq, Clone, Debug, Copy)]
+    pub enum OutputCompression {
+        None,
+        Bzip2(u32),
+        Gzip(u32),
+        Zstd(i32),
+        Xz(u32),
+        Lz4(u32),
     }
 
-    impl BufReader {
-        pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
-            let file = File::open(path)?;
-            let reader = io::BufReader::new(file);
-
-            Ok(Self { reader })
-        }
-
-        pub fn read_line<'buf>(
-            &mut self,
-            buffer: &'buf mut String,
-        ) -> Option<io::Result<&'buf mut String>> {
-            buffer.clear();
-
-            self.reader
-                .read_line(buffer)
-                .map(|u| if u == 0 { None } else { Some(buffer) })
-                .transpose()
+    impl OutputCompression {
+        pub const CODECS: [&'static str; 6] = ["none", "bzip2", "gzip", "zstd", "xz", "lz4"];
+
+        /// The codec with the given level, or its default level when `level` is
+        /// `None`. Returns `None` for an unknown codec or out of range level.
+        pub fn new(codec: &str, level: Option<i32>) -> Option<Self> {
+            let in_range = |default: i32, min: i32, max: i32| {
+                let level = level.unwrap_or(default);
+                if (min..=max).contains(&level) {
+                    Some(level)
+                } else {
+                    None
+                }
+            };
+
+            match codec {
+                "none" => Some(OutputCompression::None),
+                "bzip2" => in_range(9, 1, 9).map(|l| OutputCompression::Bzip2(l as u32)),
+                "gzip" => in_range(6, 0, 9).map(|l| OutputCompression::Gzip(l as u32)),
+                "zstd" => in_range(3, 1, 22).map(OutputCompression::Zstd),
+                "xz" => in_range(6, 0, 9).map(|l| OutputCompression::Xz(l as u32)),
+                "lz4" => in_range(0, 0, 12).map(|l| OutputCompression::Lz4(l as u32)),
+                _ => None,
+            }
         }
-    }
-}
 
-pub struct Converter<'a> {
-    reader: file_reader::BufReader,
-    builder: FlatBufferBuilder<'a>,
-    game_args: GameArgs<'a>,
-    games: Vec<WIPOffset<Game<'a>>>,
-}
-
-impl<'a> Converter<'a> {
-    fn read_header(&mut self, line: &str) {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r#"\[(.*) "(.*)"\]"#).unwrap();
+        pub fn extension(self) -> &'static str {
+            match self {
+                OutputCompression::None => "bin",
+                OutputCompression::Bzip2(_) => "bin.bz2",
+                OutputCompression::Gzip(_) => "bin.gz",
+                OutputCompression::Zstd(_) => "bin.zst",
+                OutputCompression::Xz(_) => "bin.xz",
+                OutputCompression::Lz4(_) => "bin.lz4",
+            }
         }
 
-        for cap in RE.captures_iter(line) {
-            let field = &cap[1];
-            let value = &cap[2];
-
-            match field {
-                "UTCDate" => {
-                    let date_parts: Vec<&str> = value.split('.').collect();
-
-                    self.game_args.year = date_parts[0].parse::<u16>().unwrap();
-                    self.game_args.month = date_parts[1].parse::<u8>().unwrap();
-                    self.game_args.day = date_parts[2].parse::<u8>().unwrap();
-                }
-                "TimeControl" => {
-                    if value == "-" {
-                        self.game_args.time_control_main = 0;
-                        self.game_args.time_control_increment = 0;
-                    } else {
-                        let time_control_parts: Vec<&str> = value.split('+').collect();
-                        self.game_args.time_control_main =
-                            time_control_parts[0].parse::<u16>().unwrap();
-                        self.game_args.time_control_increment =
-                            time_control_parts[1].parse::<u8>().unwrap();
-                    }
-                }
-                "WhiteElo" => {
-                    if value == "?" {
-                        self.game_args.white_rating = 0;
-                    } else {
-                        self.game_args.white_rating = value.parse::<u16>().unwrap();
-                    }
+        fn write(self, file: File, data: &[u8]) -> io::Result<()> {
+            match self {
+                OutputCompression::None => {
+                    let mut file = file;
+                    file.write_all(data)?;
                 }
-                "BlackElo" => {
-                    if value == "?" {
-                        self.game_args.black_rating = 0;
-                    } else {
-                        self.game_args.black_rating = value.parse::<u16>().unwrap();
-                    }
+                OutputCompression::Bzip2(level) => {
+                    let mut compressor = BzEncoder::new(file, bzip2::Compression::new(level));
+                    compressor.write_all(data)?;
+                    compressor.finish()?;
                 }
-                "WhiteRatingDiff" => {
-                    self.game_args.white_diff = value.parse::<i16>().unwrap();
+                OutputCompression::Gzip(level) => {
+                    let mut compressor = GzEncoder::new(file, flate2::Compression::new(level));
+                    compressor.write_all(data)?;
+                    compressor.finish()?;
                 }
-                "BlackRatingDiff" => {
-                    self.game_args.black_diff = value.parse::<i16>().unwrap();
+                OutputCompression::Zstd(level) => {
+                    let mut compressor = zstd::stream::write::Encoder::new(file, level)?;
+                    compressor.write_all(data)?;
+                    compressor.finish()?;
                 }
-                "ECO" => {
-                    if value == "?" {
-                        self.game_args.eco_category = 0;
-                        self.game_args.eco_subcategory = 0;
-                    } else {
-                        let cat_char = (&value[..1]).chars().next().unwrap();
-
-                        let mut cat_char_vec: Vec<u8> = vec![0];
-                        cat_char.encode_utf8(&mut cat_char_vec);
-
-                        self.game_args.eco_category = cat_char_vec[0] as u8;
-                        self.game_args.eco_subcategory = (&value[1..]).parse::<u8>().unwrap();
-                    }
+                OutputCompression::Xz(level) => {
+                    let mut compressor = XzEncoder::new(file, level);
+                    compressor.write_all(data)?;
+                    compressor.finish()?;
                 }
-                "Result" => {
-                    self.game_args.result = match value {
-                        "1-0" => GameResult::White as u8,
-                        "0-1" => GameResult::Black as u8,
-                        "1/2-1/2" => GameResult::Draw as u8,
-                        "*" => GameResult::Star as u8,
-                        u => panic!("Unknown result: {}", u),
-                    }
+                OutputCompression::Lz4(level) => {
+                    let mut compressor = lz4::EncoderBuilder::new().level(level).build(file)?;
+                    compressor.write_all(data)?;
+                    let (_, result) = compressor.finish();
+                    result?;
                 }
-                "Termination" => {
-                    self.game_args.termination = match value {
-                        "Normal" => Termination::Normal as u8,
-                        "Time forfeit" => Termination::TimeForfeit as u8,
-                        "Abandoned" => Termination::Abandoned as u8,
-                        "Rules infraction" => Termination::RulesInfraction as u8,
-                        "Unterminated" => Termination::Unterminated as u8,
-                        u => panic!("Unknown termination: {}", u),
-                    }
-                }
-                "Site" => {
-                    self.game_args.site = Some(self.builder.create_string(value));
-                }
-                "White" => {
-                    self.game_args.white = Some(self.builder.create_string(value));
-                }
-                "Black" => {
-                    self.game_args.black = Some(self.builder.create_string(value));
-                }
-                _ => {}
             }
-        }
-    }
 
-    fn parse_game_text(&mut self, line: &str) {
-        lazy_static! {
-            static ref RE_EVAL: Regex = Regex::new(r#"(-?\d+\.\d{1,2}|#-?\d+)"#).unwrap();
-            static ref RE_EVAL_ADVANTAGE: Regex = Regex::new(r#"(-?\d+\.\d{1,2})"#).unwrap();
-            static ref RE_EVAL_MATE: Regex = Regex::new(r#"#(-?\d+)"#).unwrap();
-            static ref RE_CLK: Regex = Regex::new(r#"(\d+):(\d{2}):(\d{2})"#).unwrap();
-            static ref RE_MOVE: Regex = Regex::new(
-                r#"^([NBRQK]?)([a-h1-9]{0,4})(x?)([a-h1-9]{2})(=?)([NBRQK]?)([+#]?)([?!]{0,2})$"#
-            )
-            .unwrap();
-            static ref RE_COORD: Regex = Regex::new(r#"^([a-h]?)([1-8]?)$"#).unwrap();
-            static ref RE_CASTLING: Regex = Regex::new(r#"^(O-O-?O?)([+#]?)([?!]{0,2})$"#).unwrap();
+            Ok(())
         }
+    }
 
-        let tokens = line.split(' ');
-
-        let mut moves: Vec<u16> = vec![];
-        let mut move_metadata: Vec<u16> = vec![];
-        let mut clk_hours: Vec<u8> = vec![];
-        let mut clk_minutes: Vec<u8> = vec![];
-        let mut clk_seconds: Vec<u8> = vec![];
-        let mut eval_mate_in: Vec<i16> = vec![];
-        let mut eval_advantage: Vec<f32> = vec![];
-
-        let mut in_comment = false;
-
-        for token in tokens {
-            if "{" == token {
-                in_comment = true;
-            }
+    pub enum OutputSink {
+        /// One file per buffer: `{prefix}_{index:06}.{extension}`
+        Files {
+            prefix: String,
+            compression: OutputCompression,
+        },
+        /// A single stream of uncompressed buffers, each preceded by its length
+        /// as a little-endian u32
+        Stdout,
+    }
 
-            if "}" == token {
-                in_comment = false;
-            }
+    pub fn write_shard_file(
+        prefix: &str,
+        index: u32,
+        compression: OutputCompression,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let path = format!("{}_{:06}.{}", prefix, index, compression.extension());
 
-            if !in_comment {
-                for cap in RE_CASTLING.captures_iter(token) {
-                    let white = moves.len() % 2 == 0;
-                    let kingside = cap[1].len() == 3;
-
-                    let piece_str = "K";
-                    let disambiguation_str = format!("e{}", if white { "1" } else { "8" });
-                    let capture_str = "";
-                    let dest_str = format!(
-                        "{}{}",
-                        if kingside { "g" } else { "c" },
-                        if white { "1" } else { "8" }
-                    );
-                    let promotion_piece = "";
-                    let check_str = &cap[2];
-                    let nag_str = &cap[3];
-
-                    let mut move_data = 0;
-                    let mut this_move_metadata = 0;
-
-                    for coord_cap in RE_COORD.captures_iter(&disambiguation_str) {
-                        move_data |= match &coord_cap[1] {
-                            "" => 0x0,
-                            "a" => 0x1,
-                            "b" => 0x2,
-                            "c" => 0x3,
-                            "d" => 0x4,
-                            "e" => 0x5,
-                            "f" => 0x6,
-                            "g" => 0x7,
-                            "h" => 0x8,
-                            u => panic!("Unrecognized file: {}", u),
-                        };
-
-                        move_data |= (match &coord_cap[2] {
-                            "" => 0x0,
-                            "1" => 0x1,
-                            "2" => 0x2,
-                            "3" => 0x3,
-                            "4" => 0x4,
-                            "5" => 0x5,
-                            "6" => 0x6,
-                            "7" => 0x7,
-                            "8" => 0x8,
-                            u => panic!("Unrecognized rank: {}", u),
-                        } << 4);
-                    }
-
-                    for coord_cap in RE_COORD.captures_iter(&dest_str) {
-                        move_data |= (match &coord_cap[1] {
-                            "" => 0x0,
-                            "a" => 0x1,
-                            "b" => 0x2,
-                            "c" => 0x3,
-                            "d" => 0x4,
-                            "e" => 0x5,
-                            "f" => 0x6,
-                            "g" => 0x7,
-                            "h" => 0x8,
-                            u => panic!("Unrecognized file: {}", u),
-                        } << 8);
-
-                        move_data |= (match &coord_cap[2] {
-                            "" => 0x0,
-                            "1" => 0x1,
-                            "2" => 0x2,
-                            "3" => 0x3,
-                            "4" => 0x4,
-                            "5" => 0x5,
-                            "6" => 0x6,
-                            "7" => 0x7,
-                            "8" => 0x8,
-                            u => panic!("Unrecognized rank: {}", u),
-                        } << 12);
-                    }
-
-                    this_move_metadata |= match piece_str {
-                        "" => 0x0001,
-                        "N" => 0x0002,
-                        "B" => 0x0003,
-                        "R" => 0x0004,
-                        "Q" => 0x0005,
-                        "K" => 0x0006,
-                        u => panic!("Unrecognized piece: {}", u),
-                    };
-
-                    this_move_metadata |= match capture_str {
-                        "" => 0x0000,
-                        "x" => 0x0008,
-                        u => panic!("Unrecognized capture flag: {}", u),
-                    };
-
-                    this_move_metadata |= match check_str {
-                        "" => 0x0000,
-                        "+" => 0x0010,
-                        "#" => 0x0020,
-                        u => panic!("Unrecognized check flag: {}", u),
-                    };
-
-                    this_move_metadata |= match nag_str {
-                        "" => 0x0000,
-                        "!" => 0x0040,
-                        "?" => 0x0080,
-                        "!!" => 0x00C0,
-                        "??" => 0x0100,
-                        "!?" => 0x0140,
-                        "?!" => 0x0180,
-                        _ => 7,
-                    };
-
-                    this_move_metadata |= match promotion_piece {
-                        "" => 0x0000,
-                        // "P" =>      0x0200
-                        "N" => 0x0400,
-                        "B" => 0x0600,
-                        "R" => 0x0800,
-                        "Q" => 0x0A00,
-                        "K" => 0x0C00,
-                        u => panic!("Unrecognized promotion piece: {}", u),
-                    };
-
-                    moves.push(move_data);
-                    move_metadata.push(this_move_metadata);
-                }
+        compression.write(File::create(path)?, data)
+    }
 
-                for cap in RE_MOVE.captures_iter(token) {
-                    let piece_str = &cap[1];
-                    let disambiguation_str = &cap[2];
-                    let capture_str = &cap[3];
-                    let dest_str = &cap[4];
-                    assert!(disambiguation_str.len() <= dest_str.len());
-                    let promotion_str = &cap[5];
-                    let promotion_piece = &cap[6];
-                    assert!(promotion_piece.len() == promotion_str.len());
-                    let check_str = &cap[7];
-                    let nag_str = &cap[8];
-
-                    let mut move_data = 0;
-                    let mut this_move_metadata = 0;
-
-                    for coord_cap in RE_COORD.captures_iter(disambiguation_str) {
-                        move_data |= match &coord_cap[1] {
-                            "" => 0x0,
-                            "a" => 0x1,
-                            "b" => 0x2,
-                            "c" => 0x3,
-                            "d" => 0x4,
-                            "e" => 0x5,
-                            "f" => 0x6,
-                            "g" => 0x7,
-                            "h" => 0x8,
-                            u => panic!("Unrecognized file: {}", u),
-                        };
-
-                        move_data |= (match &coord_cap[2] {
-                            "" => 0x0,
-                            "1" => 0x1,
-                            "2" => 0x2,
-                            "3" => 0x3,
-                            "4" => 0x4,
-                            "5" => 0x5,
-                            "6" => 0x6,
-                            "7" => 0x7,
-                            "8" => 0x8,
-                            u => panic!("Unrecognized rank: {}", u),
-                        } << 4);
-                    }
-
-                    for coord_cap in RE_COORD.captures_iter(dest_str) {
-                        move_data |= (match &coord_cap[1] {
-                            "" => 0x0,
-                            "a" => 0x1,
-                            "b" => 0x2,
-                            "c" => 0x3,
-                            "d" => 0x4,
-                            "e" => 0x5,
-                            "f" => 0x6,
-                            "g" => 0x7,
-                            "h" => 0x8,
-                            u => panic!("Unrecognized file: {}", u),
-                        } << 8);
-
-                        move_data |= (match &coord_cap[2] {
-                            "" => 0x0,
-                            "1" => 0x1,
-                            "2" => 0x2,
-                            "3" => 0x3,
-                            "4" => 0x4,
-                            "5" => 0x5,
-                            "6" => 0x6,
-                            "7" => 0x7,
-                            "8" => 0x8,
-                            u => panic!("Unrecognized rank: {}", u),
-                        } << 12);
-                    }
-
-                    this_move_metadata |= match piece_str {
-                        "" => 0x0001,
-                        "N" => 0x0002,
-                        "B" => 0x0003,
-                        "R" => 0x0004,
-                        "Q" => 0x0005,
-                        "K" => 0x0006,
-                        u => panic!("Unrecognized piece: {}", u),
-                    };
-
-                    this_move_metadata |= match capture_str {
-                        "" => 0x0000,
-                        "x" => 0x0008,
-                        u => panic!("Unrecognized capture flag: {}", u),
-                    };
-
-                    this_move_metadata |= match check_str {
-                        "" => 0x0000,
-                        "+" => 0x0010,
-                        "#" => 0x0020,
-                        u => panic!("Unrecognized check flag: {}", u),
-                    };
-
-                    this_move_metadata |= match nag_str {
-                        "" => 0x0000,
-                        "!" => 0x0040,
-                        "?" => 0x0080,
-                        "!!" => 0x00C0,
-                        "??" => 0x0100,
-                        "!?" => 0x0140,
-                        "?!" => 0x0180,
-                        _ => 7,
-                    };
-
-                    this_move_metadata |= match promotion_piece {
-                        "" => 0x0000,
-                        // "P" =>      0x0200
-                        "N" => 0x0400,
-                        "B" => 0x0600,
-                        "R" => 0x0800,
-                        "Q" => 0x0A00,
-                        "K" => 0x0C00,
-                        u => panic!("Unrecognized promotion piece: {}", u),
-                    };
-
-                    moves.push(move_data);
-                    move_metadata.push(this_move_metadata);
-                }
-            } else {
-                for cap in RE_EVAL.captures_iter(token) {
-                    self.game_args.eval_available = true;
-
-                    let eval = &cap[1];
-
-                    if let Some(cap) = RE_EVAL_MATE.captures(eval) {
-                        eval_advantage.push(0.0);
-                        eval_mate_in.push(cap[1].parse::<i16>().unwrap());
-                    }
-
-                    if let Some(cap) = RE_EVAL_ADVANTAGE.captures(eval) {
-                        eval_mate_in.push(0);
-                        eval_advantage.push(cap[1].parse::<f32>().unwrap());
-                        break;
-                    }
-                }
+    pub fn write_length_prefixed(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+        let length = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Buffer exceeds 4 GiB"))?;
 
-                for cap in RE_CLK.captures_iter(token) {
-                    clk_hours.push(cap[1].parse::<u8>().unwrap());
-                    clk_minutes.push(cap[2].parse::<u8>().unwrap());
-                    clk_seconds.push(cap[3].parse::<u8>().unwrap());
-                }
-            }
-        }
+        out.write_all(&length.to_le_bytes())?;
+        out.write_all(data)?;
+        out.flush()
+    }
 
-        self.game_args.moves = Some(self.builder.create_vector(&moves));
-        self.game_args.move_metadata = Some(self.builder.create_vector(&move_metadata));
-        self.game_args.clock_hours = Some(self.builder.create_vector(&clk_hours));
-        self.game_args.clock_minutes = Some(self.builder.create_vector(&clk_minutes));
-        self.game_args.clock_seconds = Some(self.builder.create_vector(&clk_seconds));
-        self.game_args.eval_advantage = Some(self.builder.create_vector(&eval_advantage));
-        self.game_args.eval_mate_in = Some(self.builder.create_vector(&eval_mate_in));
+    /// Games that failed to convert, copied verbatim into `{prefix}_rejects.pgn`.
+    /// The file is only created once the first game is rejected.
+    pub struct RejectSink {
+        path: String,
+        file: Option<File>,
     }
 
-    fn convert_next_game(&mut self) -> std::io::Result<bool> {
-        let mut buffer = String::new();
-
-        self.game_args = GameArgs {
-            ..Default::default()
-        };
-
-        loop {
-            let res = self.reader.read_line(&mut buffer);
-
-            match res {
-                None => return Ok(false),
-                Some(line) => {
-                    let trimmed = line?.trim();
-                    if trimmed.len() > 1 && trimmed.starts_with('[') {
-                        self.read_header(trimmed);
-                    } else {
-                        assert!(trimmed.is_empty());
-                        break;
-                    }
-                }
+    impl RejectSink {
+        pub fn new(prefix: &str) -> Self {
+            RejectSink {
+                path: format!("{}_rejects.pgn", prefix),
+                file: None,
             }
         }
 
-        let game_text = self.reader.read_line(&mut buffer).unwrap()?;
-        self.parse_game_text(game_text.trim());
-
-        let line = match self.reader.read_line(&mut buffer) {
-            Some(v) => v?,
-            None => return Ok(false),
-        };
-
-        assert!(line.trim() == "");
-
-        let game = Game::create(&mut self.builder, &self.game_args);
-        self.games.push(game);
+        pub fn write_game(&mut self, reason: &str, raw_game: &str) -> io::Result<()> {
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => self.file.insert(File::create(&self.path)?),
+            };
+
+            // A % in the first column is the PGN escape mechanism, so the
+            // rejects file can be fed back through the converter
+            writeln!(file, "% Rejected: {}", reason)?;
+            file.write_all(raw_game.as_bytes())?;
+            if !raw_game.ends_with("\n\n") {
+                writeln!(file)?;
+            }
 
-        Ok(true)
+            Ok(())
+        }
     }
+}
 
-    fn save_to_list(&mut self) -> &[u8] {
-        let vectored_games = Some(self.builder.create_vector(&self.games));
-        let game_list = GameList::create(
-            &mut self.builder,
-            &GameListArgs {
-                games: vectored_games,
-            },
-        );
-
-        self.games = vec![];
-
-        self.builder.finish(game_list, None);
-        self.builder.finished_data()
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let matches = Command::new("PGN to Flat Buffer")
+/// Parse the command line and convert or decode, returning the first error
+fn run() -> Result<(), ConvertError> {
+    let default_threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .to_string();
+
+    let mut command = Command::new("PGN to Flat Buffer")
         .version("0.1.0")
         .author("Sam Goldman")
         .about("Convert Lichess PGN files to flat buffers")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("decode")
+                .about("Print the games in output files as PGN")
+                .arg(
+                    Arg::new("files")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .required(true)
+                        .help("Output files to decode, or - for a --stdout stream on stdin"),
+                ),
+        )
         .arg(
             Arg::new("input_file")
                 .short('i')
                 .long("input_file")
                 .takes_value(true)
-                .help("The PGN to parse")
+                .help("The PGN to parse (plain, .bz2, .gz or .zst), or - for stdin")
                 .required(true),
         )
         .arg(
@@ -560,7 +221,49 @@ fn main() -> std::io::Result<()> {
                 .short('o')
                 .long("output_prefix")
                 .takes_value(true)
-                .required(true),
+                .required_unless_present("stdout"),
+        )
+        .arg(
+            Arg::new("stdout")
+                .long("stdout")
+                .conflicts_with("output_prefix")
+                .help("Write length-prefixed buffers to stdout instead of files"),
+        )
+        .arg(
+            Arg::new("on_error")
+                .long("on_error")
+                .takes_value(true)
+                .possible_values(["skip", "log", "abort", "quarantine"])
+                .default_value("abort")
+                .help(
+                    "What to do with games that fail to convert \
+                    (quarantine copies them to {output_prefix}_rejects.pgn)",
+                ),
+        )
+        .arg(
+            Arg::new("keep_headers")
+                .long("keep_headers")
+                .takes_value(true)
+                .use_value_delimiter(true)
+                .conflicts_with("skip_headers")
+                .help(
+                    "Comma-separated tags to keep out of those without a field of their own \
+                    (Event, Round, Annotator, ...), the default keeps them all. UTCTime, \
+                    WhiteTitle, BlackTitle and Opening have fields; Event is read into fields \
+                    and is also kept as a tag",
+                ),
+        )
+        .arg(
+            Arg::new("skip_headers")
+                .long("skip_headers")
+                .takes_value(true)
+                .use_value_delimiter(true)
+                .help("Comma-separated tags to drop out of those without a field of their own"),
+        )
+        .arg(
+            Arg::new("skip_bots")
+                .long("skip_bots")
+                .help("Leave out games where either player has the BOT title"),
         )
         .arg(
             Arg::new("max")
@@ -568,65 +271,149 @@ fn main() -> std::io::Result<()> {
                 .long("max")
                 .takes_value(true)
                 .default_value("10000")
-                .help("The number of games to put in each buffer"),
+                .help(
+                    "The number of games to read into each buffer; \
+                    buffers hold fewer if some of those games fail to convert",
+                ),
         )
-        .get_matches();
-
-    let input_file = matches.value_of("input_file").unwrap();
-    let output_prefix = matches.value_of("output_prefix").unwrap();
-    let max = matches.value_of("max").unwrap().parse::<u32>().unwrap();
+        .arg(
+            Arg::new("compression")
+                .short('c')
+                .long("compression")
+                .takes_value(true)
+                .possible_values(output_sink::OutputCompression::CODECS)
+                .default_value("bzip2")
+                .help("How to compress output files"),
+        )
+        .arg(
+            Arg::new("compression_level")
+                .long("compression_level")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help(
+                    "The codec's compression level (bzip2 1-9, default 9; gzip 0-9, default 6; \
+                    zstd 1-22, default 3; xz 0-9, default 6; lz4 0-12, default 0)",
+                ),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .takes_value(true)
+                .default_value(&default_threads)
+                .help("The number of threads parsing games"),
+        )
+        .arg(
+            Arg::new("compression_threads")
+                .long("compression_threads")
+                .takes_value(true)
+                .default_value(&default_threads)
+                .help("The number of threads compressing output files"),
+        );
+    let matches = command.get_matches_mut();
 
-    let mut converter = Converter {
-        reader: file_reader::BufReader::open(input_file)?,
-        builder: flatbuffers::FlatBufferBuilder::with_capacity(1024 * 1024),
-        game_args: GameArgs {
-            ..Default::default()
-        },
-        games: vec![],
-    };
+    if let Some(decode) = matches.subcommand_matches("decode") {
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
 
-    let mut i = 0;
-    let mut k = 0;
-    loop {
-        let res = converter.convert_next_game()?;
-        if !res {
-            break;
-        } else {
-            i += 1;
-            if i == max {
-                let data = converter.save_to_list();
-
-                let mut pos = 0;
-                let buffer = File::create(format!("{}_{:06}.bin.bz2", output_prefix, k))?;
-
-                let mut compressor = BzEncoder::new(buffer, Compression::best());
-
-                while pos < data.len() {
-                    let bytes_written = compressor.write(&data[pos..])?;
-                    pos += bytes_written;
+        for path in decode.values_of("files").unwrap() {
+            for buffer in reader::read_buffers(path)? {
+                for game in reader::read_games(&buffer)? {
+                    reader::write_pgn(&mut out, &game)?;
                 }
-
-                converter.builder = flatbuffers::FlatBufferBuilder::with_capacity(1024 * 1024);
-
-                i = 0;
-                k += 1;
             }
         }
+        out.flush()?;
+
+        return Ok(());
     }
 
-    if i > 0 {
-        let data = converter.save_to_list();
+    let input_file = matches.value_of("input_file").unwrap();
+    let max = matches.value_of("max").unwrap().parse::<u32>().unwrap();
+    let threads = matches
+        .value_of("threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let compression_threads = matches
+        .value_of("compression_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let on_error = match matches.value_of("on_error").unwrap() {
+        "skip" => ErrorPolicy::Skip,
+        "log" => ErrorPolicy::Log,
+        "quarantine" => ErrorPolicy::Quarantine,
+        _ => ErrorPolicy::Abort,
+    };
+
+    let tags = |arg| {
+        matches
+            .values_of(arg)
+            .map(|tags| tags.map(str::to_string).collect())
+    };
+    let headers = match (tags("keep_headers"), tags("skip_headers")) {
+        (Some(tags), _) => HeaderFilter::Only(tags),
+        (None, Some(tags)) => HeaderFilter::Except(tags),
+        (None, None) => HeaderFilter::All,
+    };
 
-        let mut pos = 0;
-        let buffer = File::create(format!("{}_{:06}.bin.bz2", output_prefix, k))?;
+    let rejects = match (on_error, matches.value_of("output_prefix")) {
+        (ErrorPolicy::Quarantine, Some(prefix)) => Some(output_sink::RejectSink::new(prefix)),
+        (ErrorPolicy::Quarantine, None) => command
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--on_error quarantine requires --output_prefix",
+            )
+            .exit(),
+        _ => None,
+    };
 
-        let mut compressor = BzEncoder::new(buffer, Compression::best());
+    let compression_level = match matches.value_of("compression_level") {
+        Some(level) => match level.parse::<i32>() {
+            Ok(level) => Some(level),
+            Err(_) => command
+                .error(
+                    ErrorKind::InvalidValue,
+                    "--compression_level must be a number",
+                )
+                .exit(),
+        },
+        None => None,
+    };
+    let compression = match output_sink::OutputCompression::new(
+        matches.value_of("compression").unwrap(),
+        compression_level,
+    ) {
+        Some(compression) => compression,
+        None => command
+            .error(
+                ErrorKind::InvalidValue,
+                "--compression_level is out of range for the codec",
+            )
+            .exit(),
+    };
 
-        while pos < data.len() {
-            let bytes_written = compressor.write(&data[pos..])?;
-            pos += bytes_written;
-        }
-    }
+    let output = match matches.value_of("output_prefix") {
+        Some(prefix) => output_sink::OutputSink::Files {
+            prefix: prefix.to_string(),
+            compression,
+        },
+        None => output_sink::OutputSink::Stdout,
+    };
 
-    Ok(())
+    pipeline::run(
+        file_reader::BufReader::open(input_file)?,
+        pipeline::Options {
+            max: max.max(1),
+            threads: threads.max(1),
+            compression_threads: compression_threads.max(1),
+            on_error,
+            converter: Converter::builder()
+                .headers(headers)
+                .skip_bots(matches.is_present("skip_bots")),
+            output,
+            rejects,
+        },
+    )
 }
@@ -0,0 +1,269 @@
+// Parallel conversion: one thread splits the input into chunks of games, a
+// pool of threads converts each chunk into a GameList buffer, and a second
+// pool compresses the buffers. Chunks are numbered as they are read and the
+// results handled strictly in that order, so the output files, rejects and
+// error reporting match a single-threaded run.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chess_convert_pgn::flatbuffer::FlatBufferSink;
+use chess_convert_pgn::{
+    ends_with_termination_marker, file_reader, ConvertError, ConverterBuilder,
+};
+
+use crate::output_sink::{self, OutputCompression, OutputSink, RejectSink};
+use crate::ErrorPolicy;
+
+pub struct Options {
+    /// The number of games read into each chunk, and so each output buffer
+    pub max: u32,
+    pub threads: usize,
+    pub compression_threads: usize,
+    pub on_error: ErrorPolicy,
+    /// The settings each chunk's converter starts from
+    pub converter: ConverterBuilder,
+    pub output: OutputSink,
+    pub rejects: Option<RejectSink>,
+}
+
+/// The raw text of consecutive games
+struct Chunk {
+    index: u64,
+    first_game: u64,
+    first_line: u64,
+    text: String,
+}
+
+struct ConvertedChunk {
+    index: u64,
+    games: u32,
+    data: Vec<u8>,
+    /// Each game that failed to convert, with its raw text
+    failures: Vec<(ConvertError, String)>,
+}
+
+type Shared<T> = Arc<Mutex<Receiver<T>>>;
+
+fn recv_shared<T>(receiver: &Shared<T>) -> Option<T> {
+    let next = receiver.lock().unwrap().recv();
+    next.ok()
+}
+
+/// Split the input into chunks of `max` games. A game starts at a header (or
+/// PGN escape) line that follows a blank line, or a header right after a line
+/// ending in a termination marker, so every game lands whole in one chunk.
+fn split<R: Read + Send>(
+    mut reader: file_reader::BufReader<R>,
+    max: u32,
+    chunk_tx: SyncSender<Chunk>,
+) -> io::Result<()> {
+    let mut buffer = String::new();
+    let mut chunk = Chunk {
+        index: 0,
+        first_game: 0,
+        first_line: 1,
+        text: String::new(),
+    };
+    let mut games_in_chunk = 0;
+    let mut previous_blank = true;
+    let mut previous_terminated = false;
+
+    while let Some(line) = reader.read_line(&mut buffer) {
+        let line = line?;
+        let trimmed = line.trim();
+
+        let header = trimmed.starts_with('[') && !trimmed.starts_with("[%");
+        if (previous_blank && (trimmed.starts_with('[') || trimmed.starts_with('%')))
+            || (previous_terminated && header)
+        {
+            if games_in_chunk == max {
+                let next = Chunk {
+                    index: chunk.index + 1,
+                    first_game: chunk.first_game + max as u64,
+                    first_line: reader.line_number(),
+                    text: String::new(),
+                };
+
+                // The receivers only go away once the run has been aborted
+                if chunk_tx.send(std::mem::replace(&mut chunk, next)).is_err() {
+                    return Ok(());
+                }
+                games_in_chunk = 0;
+            }
+            games_in_chunk += 1;
+        }
+
+        previous_blank = trimmed.is_empty();
+        previous_terminated = ends_with_termination_marker(trimmed);
+        chunk.text.push_str(line);
+    }
+
+    if !chunk.text.is_empty() {
+        let _ = chunk_tx.send(chunk);
+    }
+
+    Ok(())
+}
+
+fn convert_chunks(
+    chunk_rx: Shared<Chunk>,
+    converted_tx: SyncSender<ConvertedChunk>,
+    converter: ConverterBuilder,
+) {
+    while let Some(chunk) = recv_shared(&chunk_rx) {
+        let reader = file_reader::BufReader::from_chunk(chunk.text, chunk.first_line);
+        let mut converter = converter
+            .clone()
+            .first_game(chunk.first_game)
+            .build_from(reader);
+        let mut sink = FlatBufferSink::new();
+        let mut games = 0;
+        let mut failures = vec![];
+
+        while let Some(game) = converter.next() {
+            match game {
+                Ok(game) => {
+                    sink.add_game(&game);
+                    games += 1;
+                }
+                Err(e @ ConvertError::Io(_)) => {
+                    failures.push((e, String::new()));
+                    break;
+                }
+                Err(e) => failures.push((e, converter.raw_game().to_string())),
+            }
+        }
+
+        let converted = ConvertedChunk {
+            index: chunk.index,
+            games,
+            data: sink.finish(),
+            failures,
+        };
+
+        if converted_tx.send(converted).is_err() {
+            break;
+        }
+    }
+}
+
+fn compress_shards(
+    shard_rx: Shared<(u32, Vec<u8>)>,
+    prefix: &str,
+    compression: OutputCompression,
+) -> io::Result<()> {
+    while let Some((index, data)) = recv_shared(&shard_rx) {
+        output_sink::write_shard_file(prefix, index, compression, &data)?;
+    }
+
+    Ok(())
+}
+
+/// Apply the error policy and hand out buffers, one chunk at a time in input order
+fn write_in_order(
+    converted_rx: Receiver<ConvertedChunk>,
+    shard_tx: SyncSender<(u32, Vec<u8>)>,
+    options: &mut Options,
+) -> Result<(), ConvertError> {
+    let mut pending = BTreeMap::new();
+    let mut next_chunk = 0;
+    let mut next_shard = 0;
+
+    for converted in converted_rx {
+        pending.insert(converted.index, converted);
+
+        while let Some(chunk) = pending.remove(&next_chunk) {
+            next_chunk += 1;
+
+            for (e, raw_game) in chunk.failures {
+                match (e, options.on_error) {
+                    (e @ ConvertError::Io(_), _) | (e, ErrorPolicy::Abort) => return Err(e),
+                    (_, ErrorPolicy::Skip) => {}
+                    (e, ErrorPolicy::Log) => eprintln!("Skipping game: {}", e),
+                    (e, ErrorPolicy::Quarantine) => {
+                        if let Some(rejects) = &mut options.rejects {
+                            rejects.write_game(&e.to_string(), &raw_game)?;
+                        }
+                    }
+                }
+            }
+
+            if chunk.games == 0 {
+                continue;
+            }
+
+            match options.output {
+                OutputSink::Files { .. } => {
+                    // Only fails if every compression thread has stopped on an
+                    // error, which is reported when they are joined
+                    if shard_tx.send((next_shard, chunk.data)).is_err() {
+                        return Ok(());
+                    }
+                }
+                OutputSink::Stdout => {
+                    output_sink::write_length_prefixed(&mut io::stdout().lock(), &chunk.data)?;
+                }
+            }
+            next_shard += 1;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run<R: Read + Send>(
+    reader: file_reader::BufReader<R>,
+    mut options: Options,
+) -> Result<(), ConvertError> {
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(options.threads * 2);
+    let (converted_tx, converted_rx) = mpsc::sync_channel(options.threads * 2);
+    let (shard_tx, shard_rx) = mpsc::sync_channel(options.compression_threads * 2);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let shard_rx = Arc::new(Mutex::new(shard_rx));
+
+    let (prefix, compression, compression_threads) = match &options.output {
+        OutputSink::Files {
+            prefix,
+            compression,
+        } => (prefix.clone(), *compression, options.compression_threads),
+        OutputSink::Stdout => (String::new(), OutputCompression::None, 0),
+    };
+
+    thread::scope(|scope| {
+        let max = options.max;
+        let splitter = scope.spawn(move || split(reader, max, chunk_tx));
+
+        for _ in 0..options.threads {
+            let chunk_rx = Arc::clone(&chunk_rx);
+            let converted_tx = converted_tx.clone();
+            let converter = options.converter.clone();
+            scope.spawn(move || convert_chunks(chunk_rx, converted_tx, converter));
+        }
+        // Once the converters finish, the splitter's sends fail and it stops
+        drop(chunk_rx);
+        drop(converted_tx);
+
+        let compressors: Vec<_> = (0..compression_threads)
+            .map(|_| {
+                let shard_rx = Arc::clone(&shard_rx);
+                let prefix = &prefix;
+                scope.spawn(move || compress_shards(shard_rx, prefix, compression))
+            })
+            .collect();
+        drop(shard_rx);
+
+        let result = write_in_order(converted_rx, shard_tx, &mut options);
+
+        for compressor in compressors {
+            compressor.join().expect("Compression thread panicked")?;
+        }
+        result?;
+        splitter.join().expect("Splitter thread panicked")?;
+
+        Ok(())
+    })
+}
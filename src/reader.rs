@@ -0,0 +1,260 @@
+// Reading converted games back: decompress an output file, check the GameList
+// buffers in it and print each game as PGN. Moves are rebuilt from the SAN
+// fields (`moves` and `move_metadata`), so every variant can be decoded.
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::file_reader::{BZIP2_MAGIC, GZIP_MAGIC, ZSTD_MAGIC};
+use crate::game::{Eval, ParsedGame, ParsedMove};
+use crate::{GameList, GameResult, Termination, Variant};
+
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4D, 0x18];
+
+/// The GameList buffers in `path`: the single buffer of an output file, in any
+/// of the output codecs, or every buffer of a `--stdout` stream when `path` is `-`
+pub fn read_buffers(path: &str) -> io::Result<Vec<Vec<u8>>> {
+    if path == "-" {
+        return read_length_prefixed(&mut io::stdin().lock());
+    }
+
+    let mut source = io::BufReader::new(File::open(path)?);
+    let magic = source.fill_buf()?;
+
+    let mut decoded: Box<dyn Read> = if magic.starts_with(BZIP2_MAGIC) {
+        Box::new(MultiBzDecoder::new(source))
+    } else if magic.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(source))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(source)?)
+    } else if magic.starts_with(XZ_MAGIC) {
+        Box::new(XzDecoder::new(source))
+    } else if magic.starts_with(LZ4_MAGIC) {
+        Box::new(lz4::Decoder::new(source)?)
+    } else {
+        Box::new(source)
+    };
+
+    let mut buffer = vec![];
+    decoded.read_to_end(&mut buffer)?;
+
+    Ok(vec![buffer])
+}
+
+/// Split a stream written by `output_sink::write_length_prefixed` back into buffers
+pub fn read_length_prefixed(input: &mut impl Read) -> io::Result<Vec<Vec<u8>>> {
+    let mut buffers = vec![];
+    let mut length = [0; 4];
+
+    loop {
+        match input.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        let mut buffer = vec![0; u32::from_le_bytes(length) as usize];
+        input.read_exact(&mut buffer)?;
+        buffers.push(buffer);
+    }
+
+    Ok(buffers)
+}
+
+/// Verify and open a GameList buffer
+pub fn game_list(buffer: &[u8]) -> io::Result<GameList<'_>> {
+    crate::chess::chess::root_as_game_list(buffer)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Decode every game of a GameList buffer
+pub fn read_games(buffer: &[u8]) -> io::Result<Vec<ParsedGame>> {
+    Ok(crate::flatbuffer::read_game_list(game_list(buffer)?))
+}
+
+fn write_header(out: &mut impl Write, name: &str, value: &str) -> io::Result<()> {
+    writeln!(out, "[{} \"{}\"]", name, value)
+}
+
+fn rating(rating: Option<u16>) -> String {
+    match rating {
+        Some(rating) => rating.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/// Push the movetext tokens of a line of moves, with each move's variations in
+/// parentheses after it
+fn push_line(
+    tokens: &mut Vec<String>,
+    moves: &[ParsedMove],
+    mut move_number: u32,
+    mut black_to_move: bool,
+) {
+    // Black's move needs its number after anything that interrupts the moves
+    let mut interrupted = true;
+
+    for mv in moves.iter() {
+        if !black_to_move {
+            tokens.push(format!("{}.", move_number));
+        } else if interrupted {
+            tokens.push(format!("{}...", move_number));
+        }
+        tokens.push(mv.san.to_string());
+        tokens.extend(mv.nags.iter().map(|nag| format!("${}", nag)));
+
+        let mut comment = vec![];
+        match mv.eval {
+            Some(Eval::MateIn(mate_in)) => comment.push(format!("[%eval #{}]", mate_in)),
+            // Debug formatting keeps the decimal point that the parser expects
+            Some(Eval::Pawns(pawns)) => comment.push(format!("[%eval {:?}]", pawns)),
+            None => {}
+        }
+        if let Some(clock) = mv.clock {
+            comment.push(format!(
+                "[%clk {}:{:02}:{:02}]",
+                clock.hours, clock.minutes, clock.seconds
+            ));
+        }
+        if let Some(text) = &mv.comment {
+            comment.push(text.clone());
+        }
+        interrupted = !comment.is_empty();
+        if interrupted {
+            tokens.push(format!("{{ {} }}", comment.join(" ")));
+        }
+
+        for variation in mv.variations.iter() {
+            tokens.push("(".to_string());
+            if let Some(comment) = &variation.comment {
+                tokens.push(format!("{{ {} }}", comment));
+            }
+            push_line(tokens, &variation.moves, move_number, black_to_move);
+            tokens.push(")".to_string());
+            interrupted = true;
+        }
+
+        if black_to_move {
+            move_number += 1;
+        }
+        black_to_move = !black_to_move;
+    }
+}
+
+/// Write `game` as PGN: the headers, a blank line, the movetext on a single
+/// line with clocks, evals and comment text in a comment after each move, then
+/// a blank line
+pub fn write_pgn(out: &mut impl Write, game: &ParsedGame) -> io::Result<()> {
+    let result = game.result.map_or("*", GameResult::pgn_name);
+    let header = |tag: &str| {
+        game.headers
+            .iter()
+            .find(|(key, _)| key == tag)
+            .map(|(_, value)| value)
+    };
+
+    // The Seven Tag Roster comes first, in its usual order. Tags that were not
+    // stored are left out rather than written as "?", which would convert to a
+    // stored "?".
+    if let Some(event) = header("Event") {
+        write_header(out, "Event", event)?;
+    }
+    if let Some(site) = &game.site {
+        write_header(out, "Site", site)?;
+    }
+    if let Some(date) = header("Date") {
+        write_header(out, "Date", date)?;
+    }
+    if let Some(round) = header("Round") {
+        write_header(out, "Round", round)?;
+    }
+    if let Some(white) = &game.white {
+        write_header(out, "White", white)?;
+    }
+    if let Some(black) = &game.black {
+        write_header(out, "Black", black)?;
+    }
+    write_header(out, "Result", result)?;
+    if let Some((year, month, day)) = game.utc_date {
+        let date = format!("{:04}.{:02}.{:02}", year, month, day);
+        write_header(out, "UTCDate", &date)?;
+    }
+    if let Some((hours, minutes, seconds)) = game.utc_time {
+        let time = format!("{:02}:{:02}:{:02}", hours, minutes, seconds);
+        write_header(out, "UTCTime", &time)?;
+    }
+    write_header(out, "WhiteElo", &rating(game.white_rating))?;
+    write_header(out, "BlackElo", &rating(game.black_rating))?;
+    if game.white_diff != 0 {
+        write_header(out, "WhiteRatingDiff", &format!("{:+}", game.white_diff))?;
+    }
+    if game.black_diff != 0 {
+        write_header(out, "BlackRatingDiff", &format!("{:+}", game.black_diff))?;
+    }
+    if let Some(title) = game.white_title {
+        write_header(out, "WhiteTitle", title.pgn_name())?;
+    }
+    if let Some(title) = game.black_title {
+        write_header(out, "BlackTitle", title.pgn_name())?;
+    }
+    if game.variant != Variant::Standard {
+        write_header(out, "Variant", game.variant.pgn_name())?;
+    }
+    match game.time_control {
+        Some((main, increment)) => {
+            write_header(out, "TimeControl", &format!("{}+{}", main, increment))?
+        }
+        None => write_header(out, "TimeControl", "-")?,
+    }
+    match game.eco {
+        Some((category, subcategory)) => write_header(
+            out,
+            "ECO",
+            &format!("{}{:02}", category as char, subcategory),
+        )?,
+        None => write_header(out, "ECO", "?")?,
+    }
+    if let Some(opening) = &game.opening {
+        write_header(out, "Opening", opening)?;
+    }
+    let termination = game.termination.unwrap_or(Termination::Normal);
+    write_header(out, "Termination", termination.pgn_name())?;
+    for (key, value) in game.headers.iter() {
+        if !["Event", "Date", "Round"].contains(&key.as_str()) {
+            write_header(out, key, value)?;
+        }
+    }
+    if let Some(fen) = &game.fen {
+        write_header(out, "FEN", fen)?;
+        write_header(out, "SetUp", "1")?;
+    }
+    writeln!(out)?;
+
+    // Move numbers continue from the FEN's side to move and fullmove number
+    let fen_fields: Vec<&str> = game
+        .fen
+        .as_deref()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+    let black_to_move = fen_fields.get(1) == Some(&"b");
+    let move_number = fen_fields
+        .get(5)
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(1);
+
+    let mut tokens: Vec<String> = vec![];
+    if let Some(comment) = &game.comment {
+        tokens.push(format!("{{ {} }}", comment));
+    }
+    push_line(&mut tokens, &game.moves, move_number, black_to_move);
+    tokens.push(result.to_string());
+
+    writeln!(out, "{}", tokens.join(" "))?;
+    writeln!(out)
+}
@@ -0,0 +1,1184 @@
+// Round trips through the converter and the reader: PGN is parsed, stored in a
+// GameList, decoded back to PGN and parsed and stored again, and the games read
+// back from the two GameLists must agree field by field.
+
+use std::io::Cursor;
+
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+use chess_convert_pgn::board::{self, CastlingSide, MoveKind, PieceKind, Position};
+use chess_convert_pgn::flatbuffer::FlatBufferSink;
+use chess_convert_pgn::game::{self, Check, ParsedGame};
+use chess_convert_pgn::lexer::{Lexer, Token};
+use chess_convert_pgn::{
+    reader, ConvertError, Converter, GameResult, HeaderFilter, Speed, Termination, Title,
+    Variant,
+};
+
+/// Decode's own output for a Lichess blitz game, so it survives a round trip as text
+const LICHESS_BLITZ: &str = r#"[Event "?"]
+[Site "https://lichess.org/abcd1234"]
+[Date "2024.01.01"]
+[Round "?"]
+[White "alice"]
+[Black "bob"]
+[Result "1-0"]
+[UTCDate "2024.01.01"]
+[WhiteElo "1500"]
+[BlackElo "1480"]
+[WhiteRatingDiff "+6"]
+[BlackRatingDiff "-6"]
+[TimeControl "180+2"]
+[ECO "C20"]
+[Opening "King's Pawn Game: Wayward Queen Attack"]
+[Termination "Normal"]
+
+1. e4 { [%eval 0.2] [%clk 0:03:00] } 1... e5 { [%eval 0.25] [%clk 0:03:00] } 2. Qh5 { [%eval -0.5] [%clk 0:02:58] } 2... Nc6 { [%eval -0.43] [%clk 0:02:57] } 3. Bc4 { [%eval -0.55] [%clk 0:02:55] } 3... Nf6?? { [%eval #1] [%clk 0:02:50] } 4. Qxf7# { [%clk 0:02:54] } 1-0
+
+"#;
+
+const CORRESPONDENCE: &str = r#"[Event "Casual Correspondence game"]
+[Site "https://lichess.org/corr0001"]
+[White "carol"]
+[Black "dave"]
+[Result "1-0"]
+[UTCDate "2023.06.30"]
+[UTCTime "12:00:00"]
+[WhiteElo "?"]
+[BlackElo "?"]
+[ECO "?"]
+[TimeControl "-"]
+[Termination "Normal"]
+
+1. e4 c6 2. d4 d5 3. Nc3 dxe4 4. Nxe4 Nf6 5. Qd3?! e5 6. dxe5 Qa5+ 7. Bd2 Qxe5 8. O-O-O! Nxe4?? 9. Qd8+!! Kxd8 10. Bg5+ Kc7 11. Bd8# 1-0
+
+"#;
+
+const EN_PASSANT_AND_PROMOTION: &str = r#"[Event "Rated Rapid game"]
+[Site "https://lichess.org/rapid001"]
+[White "erin"]
+[Black "frank"]
+[Result "0-1"]
+[UTCDate "2022.02.14"]
+[WhiteElo "1710"]
+[BlackElo "1695"]
+[WhiteRatingDiff "-7"]
+[BlackRatingDiff "+7"]
+[ECO "B02"]
+[TimeControl "600+0"]
+[Termination "Time forfeit"]
+
+1. e4 Nf6 2. e5 d5 3. exd6 e6 4. dxc7 Be7 5. cxb8=Q Rxb8 6. Nf3 O-O 7. Bc4 b5 8. O-O bxc4 0-1
+
+"#;
+
+const CLASSICAL_MATE_EVAL: &str = r#"[Event "Rated Classical game"]
+[Site "https://lichess.org/class001"]
+[White "gina"]
+[Black "hal"]
+[Result "0-1"]
+[UTCDate "2021.11.05"]
+[WhiteElo "2210"]
+[BlackElo "2305"]
+[WhiteRatingDiff "-3"]
+[BlackRatingDiff "+3"]
+[ECO "A02"]
+[TimeControl "5400+30"]
+[Termination "Normal"]
+
+1. f3 { [%eval -0.6] [%clk 1:30:00] } 1... e5 { [%eval -0.5] [%clk 1:30:00] } 2. g4?? { [%eval #-1] [%clk 1:29:31] } 2... Qh4# { [%clk 1:29:55] } 0-1
+
+"#;
+
+const FROM_POSITION: &str = r#"[Event "Promotions"]
+[Site "https://lichess.org/setup001"]
+[Result "*"]
+[Variant "From Position"]
+[FEN "r3k3/1P6/8/8/8/8/1p6/4K2R w K - 0 1"]
+[SetUp "1"]
+[Termination "Unterminated"]
+
+1. bxa8=Q+ Ke7 2. O-O b1=N 3. Qb7+ Kd6 4. Rxb1 *
+
+[Event "Black to move"]
+[Site "https://lichess.org/setup002"]
+[Result "*"]
+[Variant "From Position"]
+[FEN "4k3/8/8/8/8/8/p7/4K3 b - - 0 40"]
+[SetUp "1"]
+[Termination "Abandoned"]
+
+40... a1=Q+ 41. Kd2 Qb2+ 42. Ke3 *
+
+"#;
+
+const VARIANTS: &str = r#"[Event "Chess960"]
+[Site "https://lichess.org/c960001"]
+[Result "*"]
+[Variant "Chess960"]
+[FEN "rkr5/ppp5/8/8/8/8/PPP5/RKR5 w KQkq - 0 1"]
+[SetUp "1"]
+
+1. O-O a6 2. Rfe1 *
+
+[Event "Crazyhouse"]
+[Site "https://lichess.org/zh000001"]
+[Result "*"]
+[Variant "Crazyhouse"]
+
+1. e4 d5 2. exd5 Qxd5 3. Nc3 Qa5 4. P@b4 Qxb4 5. Rb1 N@d4 6. @e2 O-O-O *
+
+"#;
+
+/// Movetext wrapped at 80 columns, as most exporters other than lichess write it
+const WRAPPED: &str = r#"[Event "Paris"]
+[Site "Paris FRA"]
+[Date "1858.??.??"]
+[Round "?"]
+[White "Morphy, Paul"]
+[Black "Duke Karl / Count Isouard"]
+[Result "1-0"]
+[ECO "C41"]
+
+1. e4 e5 2. Nf3 d6 3. d4 Bg4 4. dxe5 Bxf3 5. Qxf3 dxe5 6. Bc4 Nf6 7. Qb3 Qe7 8.
+Nc3 c6 9. Bg5 b5 10. Nxb5 cxb5 11. Bxb5+ Nbd7 12. O-O-O Rd8 13. Rxd7 Rxd7 14.
+Rd1 Qe6 15. Bxd7+ Nxd7 16. Qb8+ { A queen sacrifice to finish the
+game } 16... Nxb8 17. Rd8# 1-0
+
+"#;
+
+/// A study chapter with nested variations
+const ANNOTATED: &str = r#"[Event "Study: Italian Game"]
+[Site "https://lichess.org/study/abcdefgh"]
+[Result "*"]
+
+{ The Italian Game } 1. e4 e5 2. Nf3 Nc6 3. Bc4 $1 $14 { Aiming at f7 } ( 3. Bb5 $146 { The Spanish } a6 ( 3... Nf6 4. O-O ) 4. Ba4 ) ( { The Scotch } 3. d4 exd4 ) 3... Bc5 ( 3... Nf6 4. Ng5 d5 ( 4... Bc5 ) ) 4. c3 *
+
+"#;
+
+const CORPUS: [&str; 8] = [
+    LICHESS_BLITZ,
+    CORRESPONDENCE,
+    EN_PASSANT_AND_PROMOTION,
+    CLASSICAL_MATE_EVAL,
+    FROM_POSITION,
+    VARIANTS,
+    WRAPPED,
+    ANNOTATED,
+];
+
+/// Parse PGN, failing on any conversion error
+fn convert(pgn: &str) -> Vec<ParsedGame> {
+    Converter::builder()
+        .build(pgn.as_bytes())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+}
+
+fn serialize(games: &[ParsedGame]) -> Vec<u8> {
+    let mut sink = FlatBufferSink::new();
+    for game in games {
+        sink.add_game(game);
+    }
+    sink.finish()
+}
+
+/// The games as read back from a GameList
+fn store(games: &[ParsedGame]) -> Vec<ParsedGame> {
+    reader::read_games(&serialize(games)).unwrap()
+}
+
+fn decode(games: &[ParsedGame]) -> String {
+    let mut pgn = vec![];
+    for game in store(games) {
+        reader::write_pgn(&mut pgn, &game).unwrap();
+    }
+    String::from_utf8(pgn).unwrap()
+}
+
+/// Assert that `pgn` converts to the same games, field by field, after a trip
+/// through `decode`
+fn assert_round_trip(pgn: &str) {
+    let original = convert(pgn);
+    let decoded = decode(&original);
+    let round_tripped = convert(&decoded);
+
+    assert_eq!(store(&original), store(&round_tripped), "{}", decoded);
+}
+
+#[test]
+fn corpus_round_trips() {
+    for pgn in CORPUS {
+        assert_round_trip(pgn);
+    }
+    assert_round_trip(&CORPUS.concat());
+}
+
+#[test]
+fn decoded_pgn_matches_lichess_export() {
+    assert_eq!(decode(&convert(LICHESS_BLITZ)), LICHESS_BLITZ);
+}
+
+#[test]
+fn game_list_keeps_parsed_games() {
+    // Every header the lichess export has is stored, and each of its moves
+    // has a clock and an eval
+    let games = convert(LICHESS_BLITZ);
+    assert_eq!(store(&games), games);
+}
+
+#[test]
+fn corpus_fields() {
+    let games = convert(&CORPUS.concat());
+    assert_eq!(games.len(), 10);
+
+    let blitz = &games[0];
+    assert_eq!(blitz.white.as_deref(), Some("alice"));
+    assert_eq!(blitz.utc_date, Some((2024, 1, 1)));
+    assert_eq!((blitz.white_diff, blitz.black_diff), (6, -6));
+    assert_eq!(blitz.eco, Some((b'C', 20)));
+    assert_eq!(blitz.moves[5].eval, Some(game::Eval::MateIn(1)));
+    assert_eq!(blitz.moves[6].san.to_string(), "Qxf7#");
+    assert_eq!(blitz.moves[6].san.check, Some(Check::Mate));
+
+    let correspondence = &games[1];
+    assert_eq!(correspondence.white_rating, None);
+    assert_eq!(correspondence.black_rating, None);
+    assert_eq!(correspondence.time_control, None);
+    assert_eq!(correspondence.eco, None);
+    assert!(!correspondence.eval_available());
+
+    let promotion = &games[2];
+    assert_eq!(promotion.moves[8].san.promotion, Some(PieceKind::Queen));
+    assert_eq!(promotion.termination, Some(Termination::TimeForfeit));
+
+    let classical = &games[3];
+    assert_eq!(classical.moves[0].clock.unwrap().hours, 1);
+    assert_eq!(classical.moves[2].eval, Some(game::Eval::MateIn(-1)));
+
+    assert_eq!(games[5].moves.len(), 4);
+    assert_eq!(games[5].result, Some(GameResult::Star));
+    assert_eq!(games[7].variant, Variant::Crazyhouse);
+    assert!(games[7].moves.iter().all(|mv| mv.uci.is_none()));
+}
+
+#[test]
+fn game_list_layout() {
+    let buffer = serialize(&convert(&CORPUS.concat()));
+    let game_list = reader::game_list(&buffer).unwrap();
+    let games: Vec<_> = game_list.games().iter().flatten().collect();
+    assert_eq!(games.len(), 10);
+
+    let blitz = &games[0];
+    assert_eq!((blitz.year(), blitz.month(), blitz.day()), (2024, 1, 1));
+    assert_eq!(blitz.eval_mate_in().unwrap().get(5), 1);
+    assert_eq!(blitz.clock_seconds().unwrap().len(), 7);
+    // Qxf7#: a queen capture giving mate
+    assert_eq!(
+        blitz.move_metadata().unwrap().get(6),
+        0x0005 | 0x0008 | 0x0020
+    );
+
+    let correspondence = &games[1];
+    assert_eq!(correspondence.white_rating(), 0);
+    assert_eq!(correspondence.time_control_main(), 0);
+    assert_eq!(correspondence.eco_category(), 0);
+    assert!(!correspondence.eval_available());
+
+    // cxb8=Q: a pawn capturing and promoting to a queen
+    let promotion = &games[2];
+    assert_eq!(
+        promotion.move_metadata().unwrap().get(8),
+        0x0001 | 0x0008 | 0x0A00
+    );
+    assert_eq!(promotion.termination(), Termination::TimeForfeit as u8);
+
+    assert_eq!(games[3].clock_hours().unwrap().get(0), 1);
+    assert_eq!(games[7].variant(), Variant::Crazyhouse as u8);
+    assert_eq!(games[7].moves_full().unwrap().len(), 0);
+    assert!(games[0].variations().is_none());
+
+    let bc4 = games[9].variations().unwrap().get(0);
+    assert_eq!(bc4.ply(), 4);
+    assert_eq!(bc4.moves().unwrap().len(), 3);
+    assert_eq!(bc4.variations().unwrap().get(0).ply(), 1);
+    assert_eq!(bc4.comment(), None);
+    let d4 = games[9].variations().unwrap().get(1);
+    assert_eq!(d4.comment(), Some("The Scotch"));
+}
+
+#[test]
+fn header_passthrough() {
+    let tags = |games: &[ParsedGame]| -> Vec<String> {
+        games[0].headers.iter().map(|(key, _)| key.clone()).collect()
+    };
+    let correspondence = convert(CORRESPONDENCE);
+    assert_eq!(tags(&correspondence), ["Event"]);
+    assert_eq!(store(&correspondence), correspondence);
+
+    let filtered = |filter: HeaderFilter| -> Vec<ParsedGame> {
+        Converter::builder()
+            .headers(filter)
+            .build(Cursor::new(WRAPPED))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    };
+    let wrapped = filtered(HeaderFilter::All);
+    assert_eq!(tags(&wrapped), ["Event", "Date", "Round"]);
+    assert_eq!(
+        wrapped[0].headers[1],
+        ("Date".to_string(), "1858.??.??".to_string())
+    );
+    assert_eq!(
+        tags(&filtered(HeaderFilter::Only(vec!["Round".to_string()]))),
+        ["Round"]
+    );
+    assert_eq!(
+        tags(&filtered(HeaderFilter::Except(vec!["Round".to_string()]))),
+        ["Event", "Date"]
+    );
+
+    // Decode writes the roster tags in their usual places
+    let decoded = decode(&wrapped);
+    assert!(decoded.starts_with(
+        "[Event \"Paris\"]\n[Site \"Paris FRA\"]\n[Date \"1858.??.??\"]\n[Round \"?\"]\n"
+    ));
+}
+
+#[test]
+fn start_time() {
+    let game = &convert(CORRESPONDENCE)[0];
+    assert_eq!(game.utc_time, Some((12, 0, 0)));
+    assert_eq!(game.start_time(), Some(1_688_126_400));
+
+    let start_time = |date: &str, time: &str| {
+        let pgn = format!(
+            "[Result \"*\"]\n[UTCDate \"{}\"]\n[UTCTime \"{}\"]\n\n*\n\n",
+            date, time
+        );
+        let games = convert(&pgn);
+        assert_eq!(store(&games)[0].utc_time, games[0].utc_time);
+        games[0].start_time()
+    };
+    assert_eq!(start_time("1970.01.01", "00:00:01"), Some(1));
+    assert_eq!(start_time("1969.12.31", "23:59:59"), Some(-1));
+    assert_eq!(start_time("2000.02.29", "00:00:00"), Some(951_782_400));
+    assert_eq!(start_time("2024.03.01", "10:30:15"), Some(1_709_289_015));
+
+    // Without a UTCTime there is no start time, and the date is kept as before
+    let games = convert(CLASSICAL_MATE_EVAL);
+    assert_eq!(games[0].start_time(), None);
+    assert_eq!(store(&games)[0].utc_date, Some((2021, 11, 5)));
+
+    // A UTCTime without a UTCDate has no start time to be stored in
+    let games = convert("[Result \"*\"]\n[UTCTime \"10:30:15\"]\n\n*\n\n");
+    assert_eq!(games[0].utc_time, Some((10, 30, 15)));
+    assert_eq!(store(&games)[0].utc_time, None);
+
+    for (header, value) in [
+        ("UTCDate", "2024.00.10"),
+        ("UTCDate", "2024.13.10"),
+        ("UTCDate", "2024.04.31"),
+        ("UTCDate", "2023.02.29"),
+        ("UTCDate", "1900.02.29"),
+        ("UTCDate", "2024.01.00"),
+        ("UTCTime", "24:00:00"),
+        ("UTCTime", "12:60:00"),
+        ("UTCTime", "12:00:60"),
+    ] {
+        let pgn = format!("[Result \"*\"]\n[{} \"{}\"]\n\n*\n\n", header, value);
+        let games: Vec<_> = Converter::builder()
+            .build(Cursor::new(pgn))
+            .unwrap()
+            .collect();
+        assert!(
+            matches!(&games[..], [Err(ConvertError::InvalidHeader { .. })]),
+            "{} {}",
+            header,
+            value
+        );
+    }
+    assert_eq!(convert("[Result \"*\"]\n[UTCDate \"2024.02.29\"]\n\n*\n\n").len(), 1);
+}
+
+#[test]
+fn titles() {
+    let titled = |white: &str, black: &str| {
+        format!(
+            "[White \"w\"]\n[Black \"b\"]\n[Result \"*\"]\n[WhiteTitle \"{}\"]\n\
+            [BlackTitle \"{}\"]\n[Termination \"Normal\"]\n\n1. e4 *\n\n",
+            white, black
+        )
+    };
+    let pgn = [titled("GM", "WFM"), titled("LM", "BOT")].concat();
+    assert_round_trip(&pgn);
+
+    let games = convert(&pgn);
+    assert_eq!(games[0].white_title, Some(Title::GM));
+    assert_eq!(games[0].black_title, Some(Title::WFM));
+    assert!(!games[0].has_bot());
+    assert!(games[1].has_bot());
+    assert_eq!(store(&games), games);
+
+    let humans: Vec<_> = Converter::builder()
+        .skip_bots(true)
+        .build(Cursor::new(format!("{}{}", pgn, LICHESS_BLITZ)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(humans.len(), 2);
+    assert_eq!(humans[0].black_title, Some(Title::WFM));
+    assert_eq!(humans[1].white.as_deref(), Some("alice"));
+
+    // A title without a field is kept as a header
+    let pgn = titled("GM", "Grandmaster");
+    assert_round_trip(&pgn);
+    let games = convert(&pgn);
+    assert_eq!(games[0].black_title, None);
+    assert_eq!(
+        games[0].headers,
+        [("BlackTitle".to_string(), "Grandmaster".to_string())]
+    );
+    assert_eq!(store(&games), games);
+}
+
+#[test]
+fn opening_table() {
+    let opening = |name: &str| {
+        format!(
+            "[Result \"*\"]\n[Opening \"{}\"]\n[Termination \"Normal\"]\n\n1. e4 *\n\n",
+            name
+        )
+    };
+    let pgn = [
+        opening("Sicilian Defense: Najdorf Variation"),
+        opening("French Defense"),
+        "[Result \"*\"]\n[Termination \"Normal\"]\n\n1. e4 *\n\n".to_string(),
+        opening("Sicilian Defense: Najdorf Variation"),
+    ]
+    .concat();
+    let games = convert(&pgn);
+    assert_eq!(games[1].opening.as_deref(), Some("French Defense"));
+    assert_eq!(games[2].opening, None);
+
+    // Each name is stored once per GameList and referenced by number
+    let mut sink = FlatBufferSink::new();
+    for game in games.iter() {
+        sink.add_game(game);
+    }
+    let buffer = sink.finish();
+    let game_list = reader::game_list(&buffer).unwrap();
+    let openings: Vec<&str> = game_list.openings().unwrap().iter().collect();
+    assert_eq!(
+        openings,
+        ["Sicilian Defense: Najdorf Variation", "French Defense"]
+    );
+    let numbers: Vec<u32> = game_list.games().unwrap().iter().map(|g| g.opening()).collect();
+    assert_eq!(numbers, [1, 2, 0, 1]);
+    assert_eq!(reader::read_games(&buffer).unwrap(), games);
+
+    // The next GameList starts a table of its own
+    sink.add_game(&games[1]);
+    let buffer = sink.finish();
+    let game_list = reader::game_list(&buffer).unwrap();
+    assert_eq!(game_list.openings().unwrap().len(), 1);
+    assert_eq!(game_list.games().unwrap().get(0).opening(), 1);
+    assert_eq!(reader::read_games(&buffer).unwrap(), [games[1].clone()]);
+}
+
+#[test]
+fn event_categories() {
+    let event = |event: &str| {
+        let pgn = format!(
+            "[Event \"{}\"]\n[Result \"*\"]\n[Termination \"Normal\"]\n\n*\n\n",
+            event
+        );
+        let mut games = convert(&pgn);
+        assert_eq!(store(&games), games);
+        let game = games.remove(0);
+        (game.rated, game.speed, game.tournament_id, game.swiss_id)
+    };
+
+    assert_eq!(
+        event("Rated Blitz game"),
+        (Some(true), Some(Speed::Blitz), None, None)
+    );
+    assert_eq!(
+        event("Casual Correspondence game"),
+        (Some(false), Some(Speed::Correspondence), None, None)
+    );
+    assert_eq!(
+        event("Rated Bullet tournament https://lichess.org/tournament/x7TwXd2Q"),
+        (
+            Some(true),
+            Some(Speed::Bullet),
+            Some("x7TwXd2Q".to_string()),
+            None
+        )
+    );
+    assert_eq!(
+        event("Rated UltraBullet swiss https://lichess.org/swiss/Abc12345"),
+        (
+            Some(true),
+            Some(Speed::UltraBullet),
+            None,
+            Some("Abc12345".to_string())
+        )
+    );
+    // Variant games name the variant instead of the speed
+    assert_eq!(event("Rated Crazyhouse game"), (Some(true), None, None, None));
+    assert_eq!(event("Paris"), (None, None, None, None));
+
+    // The Event itself is still kept
+    let game = &convert(CORRESPONDENCE)[0];
+    assert_eq!(game.speed, Some(Speed::Correspondence));
+    assert_eq!(game.headers[0].1, "Casual Correspondence game");
+}
+
+#[test]
+fn wrapped_movetext() {
+    let wrapped = convert(WRAPPED);
+    let unwrapped = convert(
+        &WRAPPED
+            .replace(".\n", ". ")
+            .replace("the\ngame", "the game"),
+    );
+    assert_eq!(wrapped, unwrapped);
+    assert_eq!(wrapped[0].moves.len(), 33);
+
+    // The last game of a file need not be followed by a blank line
+    assert_eq!(convert(WRAPPED.trim_end()), wrapped);
+
+    // A marker at the end of a line inside a comment does not end the game
+    let marker_in_comment = WRAPPED.replace("sacrifice to finish the", "sacrifice, 1-0");
+    let games = convert(&marker_in_comment);
+    assert_eq!(games[0].moves.len(), 33);
+    assert_eq!(
+        games[0].moves[30].comment.as_deref(),
+        Some("A queen sacrifice, 1-0 game")
+    );
+    let marker_in_line_comment = WRAPPED.replace("{ A queen", "; 1-0\n{ A queen");
+    assert_eq!(convert(&marker_in_line_comment)[0].moves.len(), 33);
+
+    // Extra blank lines before, between and after games are skipped
+    let spaced = format!("\n\n{}\n\n{}\n\n\n", WRAPPED, WRAPPED);
+    assert_eq!(convert(&spaced), [wrapped[0].clone(), wrapped[0].clone()]);
+    assert!(convert("\n\n").is_empty());
+
+    // A game cut off after its headers is an error, not the end of the input
+    let games: Vec<_> = Converter::builder()
+        .build(Cursor::new("[Result \"*\"]\n"))
+        .unwrap()
+        .collect();
+    assert!(matches!(
+        games[..],
+        [Err(ConvertError::UnexpectedEof { .. })]
+    ));
+
+    // Errors report the line the move is on
+    let illegal = WRAPPED.replace("Rd1 Qe6", "Rd1 Qe5");
+    let games: Vec<_> = Converter::builder()
+        .build(Cursor::new(illegal))
+        .unwrap()
+        .collect();
+    assert!(matches!(
+        games[..],
+        [Err(ConvertError::IllegalMove { line: 12, .. })]
+    ));
+}
+
+#[test]
+fn lexer_tokens() {
+    let movetext =
+        "1.e4{[%clk 0:01:00]}(1.d4 $2)1...e5!? $1 ; to the end of the line\n2. Nf3 1/2-1/2";
+    let tokens: Vec<(u64, Token)> = Lexer::new(movetext, 7).collect();
+
+    assert_eq!(
+        tokens,
+        vec![
+            (7, Token::MoveNumber(1)),
+            (7, Token::Symbol("e4")),
+            (7, Token::Comment("[%clk 0:01:00]")),
+            (7, Token::VariationStart),
+            (7, Token::MoveNumber(1)),
+            (7, Token::Symbol("d4")),
+            (7, Token::Nag(2)),
+            (7, Token::VariationEnd),
+            (7, Token::MoveNumber(1)),
+            (7, Token::Symbol("e5!?")),
+            (7, Token::Nag(1)),
+            (7, Token::Comment(" to the end of the line")),
+            (8, Token::MoveNumber(2)),
+            (8, Token::Symbol("Nf3")),
+            (8, Token::Result(GameResult::Draw)),
+        ]
+    );
+}
+
+#[test]
+fn compact_movetext() {
+    // Comments without spaces, variations and NAGs
+    let compact = "[Result \"1-0\"]\n\n1.e4{[%eval 0.3][%clk 0:01:00]}e5(1...c5 2.Nf3{Sicilian}(2.c3))2.Qh5 $2 Nc6 3.Bc4 Nf6$4 4.Qxf7# 1-0\n\n";
+    let spaced = "[Result \"1-0\"]\n\n1. e4 { [%eval 0.3] [%clk 0:01:00] } 1... e5 ( 1... c5 2. Nf3 { Sicilian } ( 2. c3 ) ) 2. Qh5? Nc6 3. Bc4 Nf6?? 4. Qxf7# 1-0\n\n";
+    assert_eq!(convert(compact), convert(spaced));
+
+    let unbalanced = "[Result \"*\"]\n\n1. e4 e5) *\n\n";
+    let games: Vec<_> = Converter::builder()
+        .build(Cursor::new(unbalanced))
+        .unwrap()
+        .collect();
+    assert!(matches!(
+        &games[..],
+        [Err(ConvertError::InvalidMove { token, .. })] if token == ")"
+    ));
+}
+
+#[test]
+fn variations() {
+    let game = &convert(ANNOTATED)[0];
+    let sans = |line: &[game::ParsedMove]| -> Vec<String> {
+        line.iter().map(|mv| mv.san.to_string()).collect()
+    };
+
+    assert_eq!(
+        sans(&game.moves),
+        ["e4", "e5", "Nf3", "Nc6", "Bc4!", "Bc5", "c3"]
+    );
+
+    // 3. Bc4 has two alternatives, and the first has one of its own
+    let bc4 = &game.moves[4];
+    assert_eq!(bc4.variations.len(), 2);
+    assert_eq!(sans(&bc4.variations[0].moves), ["Bb5", "a6", "Ba4"]);
+    assert_eq!(
+        sans(&bc4.variations[0].moves[1].variations[0].moves),
+        ["Nf6", "O-O"]
+    );
+    assert_eq!(sans(&bc4.variations[1].moves), ["d4", "exd4"]);
+
+    // Variations are replayed from the position before the move they replace
+    let castle = &bc4.variations[0].moves[1].variations[0].moves[1];
+    assert_eq!(castle.uci.unwrap().to, board::parse_square("g1").unwrap());
+
+    let bc5 = &game.moves[5];
+    assert_eq!(sans(&bc5.variations[0].moves), ["Nf6", "Ng5", "d5"]);
+    assert_eq!(
+        sans(&bc5.variations[0].moves[2].variations[0].moves),
+        ["Bc5"]
+    );
+
+    assert_eq!(store(std::slice::from_ref(game))[0].moves, game.moves);
+
+    for movetext in ["( 1. d4 ) 1. e4 *", "1. e4 ( 1. e5 ) *"] {
+        let pgn = format!("[Result \"*\"]\n\n{}\n\n", movetext);
+        let games: Vec<_> = Converter::builder()
+            .build(Cursor::new(pgn))
+            .unwrap()
+            .collect();
+        assert!(games[0].is_err(), "{}", movetext);
+    }
+}
+
+#[test]
+fn numeric_nags() {
+    let game = &convert(ANNOTATED)[0];
+    let bc4 = &game.moves[4];
+    assert_eq!(bc4.san.to_string(), "Bc4!");
+    assert_eq!(bc4.nags, [14]);
+    assert_eq!(bc4.variations[0].moves[0].nags, [146]);
+
+    // Only the first suffix annotation fits in the move's metadata
+    let pgn = "[Result \"*\"]\n\n1. e4?! $2 $18 $255 e5 $0 *\n\n";
+    let game = &convert(pgn)[0];
+    assert_eq!(game.moves[0].san.to_string(), "e4?!");
+    assert_eq!(game.moves[0].nags, [2, 18, 255]);
+    assert_eq!(game.moves[1].nags, [0]);
+    assert_eq!(store(std::slice::from_ref(game))[0].moves, game.moves);
+
+    let mut pgn = vec![];
+    reader::write_pgn(&mut pgn, game).unwrap();
+    assert!(String::from_utf8(pgn)
+        .unwrap()
+        .contains("1. e4?! $2 $18 $255 e5 $0 *"));
+}
+
+#[test]
+fn comments() {
+    let game = &convert(ANNOTATED)[0];
+    assert_eq!(game.comment.as_deref(), Some("The Italian Game"));
+    assert_eq!(game.moves[4].comment.as_deref(), Some("Aiming at f7"));
+    assert_eq!(
+        game.moves[4].variations[0].moves[0].comment.as_deref(),
+        Some("The Spanish")
+    );
+    assert_eq!(game.moves[5].comment, None);
+
+    // A comment before the first move of a variation belongs to the variation
+    let scotch = &game.moves[4].variations[1];
+    assert_eq!(scotch.comment.as_deref(), Some("The Scotch"));
+    assert_eq!(scotch.moves[0].comment, None);
+    assert_eq!(game.moves[4].variations[0].comment, None);
+    assert_eq!(store(std::slice::from_ref(game))[0].moves, game.moves);
+
+    let mut pgn = vec![];
+    reader::write_pgn(&mut pgn, game).unwrap();
+    assert!(String::from_utf8(pgn)
+        .unwrap()
+        .contains("( { The Scotch } 3. d4 exd4 )"));
+
+    // Line breaks in a comment become spaces
+    let game = &convert(WRAPPED)[0];
+    assert_eq!(
+        game.moves[30].comment.as_deref(),
+        Some("A queen sacrifice to finish the game")
+    );
+
+    // Commands are taken out of the text, and a move's comments are joined
+    let pgn = "[Result \"*\"]\n\n1. e4 { [%eval 0.3] Best by test [%clk 0:01:00] } { sharp } e5 ; Open\n*\n\n";
+    let game = &convert(pgn)[0];
+    assert_eq!(game.moves[0].comment.as_deref(), Some("Best by test sharp"));
+    assert_eq!(game.moves[1].comment.as_deref(), Some("Open"));
+    assert!(game.moves[0].clock.is_some());
+    assert_eq!(store(std::slice::from_ref(game))[0].moves, game.moves);
+
+    let mut pgn = vec![];
+    reader::write_pgn(&mut pgn, game).unwrap();
+    assert!(String::from_utf8(pgn)
+        .unwrap()
+        .contains("1. e4 { [%eval 0.3] [%clk 0:01:00] Best by test sharp } 1... e5 { Open } *"));
+
+    // Only eval- and clock-only comments leave no text
+    assert!(convert(LICHESS_BLITZ)[0]
+        .moves
+        .iter()
+        .all(|mv| mv.comment.is_none()));
+}
+
+/// Replay `movetext` from `fen`, returning the token of the first illegal move
+fn first_illegal_move(fen: &str, movetext: &str) -> Option<String> {
+    let pgn = format!(
+        "[Result \"*\"]\n[FEN \"{}\"]\n[SetUp \"1\"]\n\n{} *\n\n",
+        fen, movetext
+    );
+    let mut converter = Converter::builder().build(Cursor::new(pgn)).unwrap();
+    match converter.next().unwrap() {
+        Ok(_) => None,
+        Err(ConvertError::IllegalMove { token, .. }) => Some(token),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[test]
+fn illegal_moves() {
+    // A knight pinned to its king by a rook
+    let pinned = "4r1k1/8/8/8/8/8/4N3/4K3 w - - 0 1";
+    assert_eq!(first_illegal_move(pinned, "1. Nc3"), Some("Nc3".to_string()));
+    assert_eq!(first_illegal_move(pinned, "1. Kd1"), None);
+
+    // Castling out of check, and through an attacked square
+    let in_check = "4r1k1/8/8/8/8/8/8/R3K2R w KQ - 0 1";
+    assert_eq!(first_illegal_move(in_check, "1. O-O"), Some("O-O".to_string()));
+    assert_eq!(first_illegal_move(in_check, "1. O-O-O"), Some("O-O-O".to_string()));
+    let through_check = "5rk1/8/8/8/8/8/8/R3K2R w KQ - 0 1";
+    assert_eq!(first_illegal_move(through_check, "1. O-O"), Some("O-O".to_string()));
+    assert_eq!(first_illegal_move(through_check, "1. O-O-O"), None);
+
+    // Castling once the rook has moved away and back
+    let castles = "r3k3/8/8/8/8/8/8/R3K2R w KQq - 0 1";
+    assert_eq!(
+        first_illegal_move(castles, "1. Rh2 Ke7 2. Rh1 Ke8 3. O-O"),
+        Some("O-O".to_string())
+    );
+    assert_eq!(
+        first_illegal_move(castles, "1. Rh2 Ke7 2. Rh1 Ke8 3. O-O-O"),
+        None
+    );
+
+    // Two knights reach d7, so the move needs their file
+    let knights = "7k/8/1N3N2/8/8/8/8/4K3 w - - 0 1";
+    assert_eq!(first_illegal_move(knights, "1. Nd7"), Some("Nd7".to_string()));
+    assert_eq!(first_illegal_move(knights, "1. Nbd7"), None);
+
+    // Taking en passant would open the rank to the king
+    let en_passant = "4k3/2p5/8/KP5r/8/8/8/8 b - - 0 1";
+    assert_eq!(
+        first_illegal_move(en_passant, "1... c5 2. bxc6"),
+        Some("bxc6".to_string())
+    );
+    assert_eq!(first_illegal_move(en_passant, "1... c5 2. Ka6"), None);
+    let no_rook = "4k3/2p5/8/KP6/8/8/8/8 b - - 0 1";
+    assert_eq!(first_illegal_move(no_rook, "1... c5 2. bxc6"), None);
+    assert_eq!(
+        first_illegal_move(no_rook, "1... c5 2. c6"),
+        Some("c6".to_string())
+    );
+
+    // A capture needs its x, and a move with one must take a piece
+    let pawns = "4k3/8/8/8/4p3/3P4/8/4K3 w - - 0 1";
+    assert_eq!(first_illegal_move(pawns, "1. e4"), Some("e4".to_string()));
+    assert_eq!(first_illegal_move(pawns, "1. dxe4"), None);
+    assert_eq!(first_illegal_move(pawns, "1. d4"), None);
+    assert_eq!(first_illegal_move(pawns, "1. Kxd2"), Some("Kxd2".to_string()));
+    let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    assert_eq!(first_illegal_move(start, "1. Nxf3"), Some("Nxf3".to_string()));
+    assert_eq!(first_illegal_move(start, "1. Nf3"), None);
+}
+
+#[test]
+fn failed_games_are_skipped() {
+    let pgn = format!(
+        "[Result \"*\"]\n\n1. e4 e5 2. Ke3 *\n\n{}",
+        EN_PASSANT_AND_PROMOTION
+    );
+    let games: Vec<_> = Converter::builder()
+        .first_game(10)
+        .build(Cursor::new(pgn))
+        .unwrap()
+        .collect();
+
+    assert_eq!(games.len(), 2);
+    assert!(matches!(
+        games[0],
+        Err(ConvertError::IllegalMove {
+            game: 10,
+            line: 3,
+            ..
+        })
+    ));
+    assert_eq!(games[1].as_ref().unwrap().white.as_deref(), Some("erin"));
+}
+
+#[test]
+fn variant_fens() {
+    let crazyhouse = "r1bk3r/ppp2ppp/2n5/8/8/8/PPP2PPP/R1BQ~K2R/QNbn w KQ - 0 10";
+    let three_check = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 3+3 0 2";
+    // The Variant comes before the FEN in one game and after it in the other
+    let pgn = format!(
+        "[Result \"*\"]\n[Variant \"Crazyhouse\"]\n[FEN \"{}\"]\n[SetUp \"1\"]\n\n\
+         10. N@e5 *\n\n\
+         [Result \"*\"]\n[FEN \"{}\"]\n[SetUp \"1\"]\n[Variant \"Three-check\"]\n\n\
+         2. Nf3 *\n\n",
+        crazyhouse, three_check
+    );
+
+    let games = convert(&pgn);
+    assert_eq!(games[0].fen.as_deref(), Some(crazyhouse));
+    assert_eq!(games[1].fen.as_deref(), Some(three_check));
+    // Three-check is replayed, with the checks remaining left out of the position
+    assert!(games[1].moves[0].uci.is_some());
+    assert_round_trip(&pgn);
+
+    // A game that is replayed still needs a FEN a Position can hold
+    let pgn = format!("[Result \"*\"]\n[FEN \"{}\"]\n[SetUp \"1\"]\n\n1. e4 *\n\n", crazyhouse);
+    let games: Vec<_> = Converter::builder().build(pgn.as_bytes()).unwrap().collect();
+    assert!(matches!(
+        &games[..],
+        [Err(ConvertError::InvalidHeader { line: 2, header, .. })] if header == "FEN"
+    ));
+}
+
+#[test]
+fn malformed_games_are_read_whole() {
+    // White of each game read, or the error and raw text of each failed game
+    let read = |pgn: &str| {
+        let mut converter = Converter::builder()
+            .build(Cursor::new(pgn.to_string()))
+            .unwrap();
+        let mut games = vec![];
+        while let Some(game) = converter.next() {
+            games.push(match game {
+                Ok(game) => Ok(game.white.unwrap()),
+                Err(e) => Err((e, converter.raw_game().to_string())),
+            });
+        }
+        games
+    };
+    let next = "[White \"b\"]\n\n1. d4 *\n\n[White \"c\"]\n\n1. Ke2 *\n\n";
+
+    // A stray line among the headers fails the game, movetext and all
+    let stray = "[White \"a\"]\ngarbage\n[Black \"x\"]\n\n1. e4 e5 1-0\n\n";
+    let games = read(&format!("{}{}", stray, next));
+    assert_eq!(games.len(), 3);
+    assert!(matches!(
+        &games[0],
+        Err((ConvertError::UnexpectedLine { game: 0, line: 2, text }, raw))
+            if text == "garbage" && raw == stray
+    ));
+    assert_eq!(games[1].as_ref().ok().map(String::as_str), Some("b"));
+    assert!(matches!(
+        games[2],
+        Err((ConvertError::IllegalMove { game: 2, .. }, _))
+    ));
+
+    // The next game may start right after the termination marker
+    let games = read(&format!("[White \"a\"]\n\n1. e4 e5 1-0\n{}", next));
+    assert_eq!(games.len(), 3);
+    assert_eq!(games[0].as_ref().ok().map(String::as_str), Some("a"));
+    assert_eq!(games[1].as_ref().ok().map(String::as_str), Some("b"));
+    assert!(matches!(
+        games[2],
+        Err((ConvertError::IllegalMove { game: 2, .. }, _))
+    ));
+
+    // A game without movetext ends where the next game's headers start
+    let headers_only = "[White \"a\"]\n\n";
+    let games = read(&format!("{}{}", headers_only, next));
+    assert_eq!(games.len(), 3);
+    assert!(matches!(
+        &games[0],
+        Err((ConvertError::UnexpectedLine { game: 0, line: 3, .. }, raw)) if raw == headers_only
+    ));
+    assert_eq!(games[1].as_ref().ok().map(String::as_str), Some("b"));
+}
+
+/// The SAN of a legal move, disambiguated against the other legal moves
+fn san(position: &Position, mv: &board::Move) -> String {
+    let piece = position.piece_at(mv.from).unwrap().kind;
+    let to = board::square_name(mv.to);
+    let from = board::square_name(mv.from);
+
+    let mut san = match mv.kind {
+        MoveKind::Castle {
+            side: CastlingSide::King,
+            ..
+        } => "O-O".to_string(),
+        MoveKind::Castle { .. } => "O-O-O".to_string(),
+        _ => {
+            let capture = position.piece_at(mv.to).is_some() || mv.kind == MoveKind::EnPassant;
+            let mut san = piece.to_san().to_string();
+
+            if piece == PieceKind::Pawn {
+                if capture {
+                    san.push_str(&from[..1]);
+                }
+            } else {
+                let rivals: Vec<board::Move> = position
+                    .legal_moves()
+                    .into_iter()
+                    .filter(|other| {
+                        other.to == mv.to
+                            && other.from != mv.from
+                            && position.piece_at(other.from).unwrap().kind == piece
+                    })
+                    .collect();
+
+                let unique = |coord: fn(board::Square) -> u8| {
+                    rivals
+                        .iter()
+                        .all(|other| coord(other.from) != coord(mv.from))
+                };
+
+                if rivals.is_empty() {
+                    // No disambiguation needed
+                } else if unique(board::file_of) {
+                    san.push_str(&from[..1]);
+                } else if unique(board::rank_of) {
+                    san.push_str(&from[1..]);
+                } else {
+                    san.push_str(&from);
+                }
+            }
+
+            if capture {
+                san.push('x');
+            }
+            san.push_str(&to);
+            if let Some(promotion) = mv.promotion {
+                san.push('=');
+                san.push_str(promotion.to_san());
+            }
+            san
+        }
+    };
+
+    let mut next = position.clone();
+    next.play(mv);
+    if next.in_check(next.side_to_move()) {
+        san.push(if next.legal_moves().is_empty() {
+            '#'
+        } else {
+            '+'
+        });
+    }
+    san
+}
+
+#[derive(Debug, Clone)]
+enum Eval {
+    Centipawns(i32),
+    MateIn(i16),
+}
+
+#[derive(Debug, Clone)]
+struct Ply {
+    choice: Index,
+    nag: usize,
+    clock: (u8, u8, u8),
+    eval: Eval,
+}
+
+#[derive(Debug, Clone)]
+struct Headers {
+    date: (u16, u8, u8),
+    ratings: (Option<u16>, Option<u16>),
+    diff: i16,
+    eco: Option<(char, u8)>,
+    time_control: Option<(u16, u8)>,
+    result: usize,
+    termination: usize,
+}
+
+fn headers() -> impl Strategy<Value = Headers> {
+    (
+        (2013u16..2030, 1u8..=12, 1u8..=28),
+        (
+            prop::option::of(600u16..3000),
+            prop::option::of(600u16..3000),
+        ),
+        -50i16..50,
+        prop::option::of((
+            prop::sample::select(vec!['A', 'B', 'C', 'D', 'E']),
+            0u8..100,
+        )),
+        prop::option::of((0u16..10800, 0u8..180)),
+        0usize..4,
+        0usize..5,
+    )
+        .prop_map(
+            |(date, ratings, diff, eco, time_control, result, termination)| Headers {
+                date,
+                ratings,
+                diff,
+                eco,
+                time_control,
+                result,
+                termination,
+            },
+        )
+}
+
+fn ply() -> impl Strategy<Value = Ply> {
+    (
+        any::<Index>(),
+        0usize..7,
+        (0u8..3, 0u8..60, 0u8..60),
+        prop_oneof![
+            (-3000i32..3000).prop_map(Eval::Centipawns),
+            (1i16..20).prop_map(Eval::MateIn),
+            (-20i16..0).prop_map(Eval::MateIn),
+        ],
+    )
+        .prop_map(|(choice, nag, clock, eval)| Ply {
+            choice,
+            nag,
+            clock,
+            eval,
+        })
+}
+
+/// A Lichess-style PGN game made of random legal moves
+fn random_game(headers: &Headers, plies: &[Ply], clocks: bool, evals: bool) -> String {
+    const RESULTS: [&str; 4] = ["1-0", "0-1", "1/2-1/2", "*"];
+    const TERMINATIONS: [&str; 5] = [
+        "Normal",
+        "Time forfeit",
+        "Abandoned",
+        "Rules infraction",
+        "Unterminated",
+    ];
+    const NAGS: [&str; 7] = ["", "!", "?", "!!", "??", "!?", "?!"];
+
+    let rating = |rating: Option<u16>| rating.map_or("?".to_string(), |r| r.to_string());
+    let (year, month, day) = headers.date;
+    let mut pgn = format!(
+        "[Event \"Rated Blitz game\"]\n\
+        [Site \"https://lichess.org/random01\"]\n\
+        [White \"white\"]\n\
+        [Black \"black\"]\n\
+        [Result \"{}\"]\n\
+        [UTCDate \"{:04}.{:02}.{:02}\"]\n\
+        [WhiteElo \"{}\"]\n\
+        [BlackElo \"{}\"]\n\
+        [WhiteRatingDiff \"{:+}\"]\n\
+        [BlackRatingDiff \"{:+}\"]\n",
+        RESULTS[headers.result],
+        year,
+        month,
+        day,
+        rating(headers.ratings.0),
+        rating(headers.ratings.1),
+        headers.diff,
+        -headers.diff,
+    );
+    match headers.eco {
+        Some((category, subcategory)) => {
+            pgn.push_str(&format!("[ECO \"{}{:02}\"]\n", category, subcategory))
+        }
+        None => pgn.push_str("[ECO \"?\"]\n"),
+    }
+    match headers.time_control {
+        Some((main, increment)) => {
+            pgn.push_str(&format!("[TimeControl \"{}+{}\"]\n", main, increment))
+        }
+        None => pgn.push_str("[TimeControl \"-\"]\n"),
+    }
+    pgn.push_str(&format!(
+        "[Termination \"{}\"]\n\n",
+        TERMINATIONS[headers.termination]
+    ));
+
+    let mut position = Position::starting();
+    let mut tokens = vec![];
+
+    for (index, ply) in plies.iter().enumerate() {
+        let moves = position.legal_moves();
+        if moves.is_empty() {
+            break;
+        }
+        let mv = moves[ply.choice.index(moves.len())];
+
+        let move_number = index / 2 + 1;
+        if index % 2 == 0 {
+            tokens.push(format!("{}.", move_number));
+        } else if clocks || evals {
+            tokens.push(format!("{}...", move_number));
+        }
+        tokens.push(format!("{}{}", san(&position, &mv), NAGS[ply.nag]));
+        position.play(&mv);
+
+        let mut comment = vec![];
+        if evals {
+            comment.push(match ply.eval {
+                Eval::Centipawns(cp) => format!("[%eval {:.2}]", cp as f32 / 100.0),
+                Eval::MateIn(n) => format!("[%eval #{}]", n),
+            });
+        }
+        if clocks {
+            let (hours, minutes, seconds) = ply.clock;
+            comment.push(format!("[%clk {}:{:02}:{:02}]", hours, minutes, seconds));
+        }
+        if !comment.is_empty() {
+            tokens.push(format!("{{ {} }}", comment.join(" ")));
+        }
+    }
+    tokens.push(RESULTS[headers.result].to_string());
+
+    pgn.push_str(&tokens.join(" "));
+    pgn.push_str("\n\n");
+    pgn
+}
+
+proptest! {
+    #[test]
+    fn random_legal_games_round_trip(
+        headers in headers(),
+        plies in prop::collection::vec(ply(), 0..120),
+        clocks in any::<bool>(),
+        evals in any::<bool>(),
+    ) {
+        let pgn = random_game(&headers, &plies, clocks, evals);
+        assert_round_trip(&pgn);
+    }
+}